@@ -0,0 +1,216 @@
+use {
+    crate::stats::{Percentiles, Stats},
+    serde_json::{json, Value},
+    std::time::Duration,
+    wayland_copy_capture_test_client::{
+        monotonic_now, BufferFormat, BufferType, CaptureClient, CaptureEvent, Error, Pacing,
+        SessionOptions, Source,
+    },
+};
+
+pub struct Bench {
+    /// Frames captured by every run.
+    pub frames: u64,
+    /// The buffer counts to try.
+    pub buffers: Vec<u32>,
+    /// Longest time a single run may take.
+    pub timeout: Duration,
+}
+
+/// Captures the source with every combination of buffer type, offered format and buffer
+/// count and returns the report.
+///
+/// Errors that only affect one combination are recorded in the report. Protocol errors
+/// end the benchmark.
+pub fn run(
+    client: &mut CaptureClient,
+    source: &Source,
+    options: &SessionOptions,
+    bench: &Bench,
+) -> Result<Value, Error> {
+    let mut runs = vec![];
+    for buffer_type in [BufferType::Shm, BufferType::Dmabuf] {
+        let options = SessionOptions {
+            buffer_type,
+            displayable: false,
+            restart: false,
+            ..options.clone()
+        };
+        let formats = match probe_formats(client, source, &options, bench.timeout) {
+            Ok(Some(formats)) => formats,
+            Ok(None) => {
+                runs.push(json!({
+                    "buffer_type": buffer_type_name(buffer_type),
+                    "error": "timed out waiting for the buffer constraints",
+                }));
+                continue;
+            }
+            Err(e @ Error::Protocol(_)) => return Err(e),
+            Err(e) => {
+                runs.push(json!({
+                    "buffer_type": buffer_type_name(buffer_type),
+                    "error": e.to_string(),
+                }));
+                continue;
+            }
+        };
+        if formats.is_empty() {
+            runs.push(json!({
+                "buffer_type": buffer_type_name(buffer_type),
+                "error": "no usable formats offered",
+            }));
+        }
+        for format in formats {
+            for &buffers in &bench.buffers {
+                let options = SessionOptions {
+                    shm_format: match format {
+                        BufferFormat::Shm(f) => Some(f),
+                        _ => None,
+                    },
+                    dmabuf_format: match format {
+                        BufferFormat::Dmabuf(f) => Some(f),
+                        _ => None,
+                    },
+                    buffers: buffers as usize,
+                    pacing: Pacing::Unlimited,
+                    ..options.clone()
+                };
+                runs.push(run_once(client, source, options, format, bench)?);
+            }
+        }
+    }
+    Ok(json!({
+        "source": crate::source_name(source),
+        "frames_per_run": bench.frames,
+        "runs": runs,
+    }))
+}
+
+/// Returns the formats offered for the buffer type without capturing any frames or
+/// `None` if the compositor did not send them in time.
+fn probe_formats(
+    client: &mut CaptureClient,
+    source: &Source,
+    options: &SessionOptions,
+    timeout: Duration,
+) -> Result<Option<Vec<BufferFormat>>, Error> {
+    let options = SessionOptions {
+        pacing: Pacing::OnDemand,
+        ..options.clone()
+    };
+    let id = client.create_session(source, options)?;
+    let deadline = monotonic_now() + timeout;
+    let res = loop {
+        if let Err(e) = client.dispatch_pending() {
+            break Err(e);
+        }
+        if let Some(formats) = client.buffer_formats(id) {
+            break Ok(Some(formats));
+        }
+        if monotonic_now() >= deadline {
+            break Ok(None);
+        }
+        if let Err(e) = client.read_events(Some(deadline)) {
+            break Err(e);
+        }
+    };
+    client.destroy_session(id);
+    res
+}
+
+fn run_once(
+    client: &mut CaptureClient,
+    source: &Source,
+    options: SessionOptions,
+    format: BufferFormat,
+    bench: &Bench,
+) -> Result<Value, Error> {
+    let buffer_type = buffer_type_name(options.buffer_type);
+    let format_name = match format {
+        BufferFormat::Shm(f) => format!("{f:?}"),
+        BufferFormat::Dmabuf(f) => format!("{f:?}"),
+    };
+    let buffers = options.buffers;
+    let id = client.create_session(source, options)?;
+    let mut stats = Stats::new(None);
+    let mut frames = 0;
+    let mut error = None;
+    let mut stopped = false;
+    let start = monotonic_now();
+    let deadline = start + bench.timeout;
+    while frames < bench.frames && !stopped && monotonic_now() < deadline {
+        match client.dispatch_pending() {
+            Ok(()) => {}
+            Err(e @ Error::Protocol(_)) => return Err(e),
+            Err(e) => {
+                error = Some(e.to_string());
+                break;
+            }
+        }
+        while let Some(event) = client.next_event() {
+            match event {
+                CaptureEvent::Frame(frame) if frame.session == id => {
+                    stats.frame_ready(
+                        frame.capture_time,
+                        frame.ready_time,
+                        frame.presentation_time,
+                    );
+                    client.release(&frame);
+                    frames += 1;
+                }
+                CaptureEvent::FrameFailed { session, .. } if session == id => {
+                    stats.frame_failed();
+                }
+                CaptureEvent::Stopped(session) if session == id => stopped = true,
+                _ => {}
+            }
+        }
+        client.read_events(Some(deadline))?;
+    }
+    let elapsed = monotonic_now().saturating_sub(start);
+    let failures = client
+        .failures(id)
+        .map(|f| (f.buffer_constraints(), f.stopped(), f.unknown()))
+        .unwrap_or_default();
+    client.destroy_session(id);
+    let s = stats.summary();
+    Ok(json!({
+        "buffer_type": buffer_type,
+        "format": format_name,
+        "buffers": buffers,
+        "frames": s.frames,
+        "elapsed_s": elapsed.as_secs_f64(),
+        "fps": s.fps,
+        "latency_ms": percentiles_json(&s.latency),
+        "interval_ms": percentiles_json(&s.interval),
+        "dropped": s.dropped,
+        "failed": {
+            "total": s.failed,
+            "buffer_constraints": failures.0,
+            "stopped": failures.1,
+            "unknown": failures.2,
+        },
+        "completed": frames >= bench.frames,
+        "stopped": stopped,
+        "error": error,
+    }))
+}
+
+fn buffer_type_name(buffer_type: BufferType) -> &'static str {
+    match buffer_type {
+        BufferType::Shm => "shm",
+        BufferType::Dmabuf => "dmabuf",
+    }
+}
+
+fn percentiles_json(p: &Percentiles) -> Value {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    json!({
+        "min": ms(p.min),
+        "mean": ms(p.mean),
+        "p50": ms(p.p50),
+        "p95": ms(p.p95),
+        "p99": ms(p.p99),
+        "max": ms(p.max),
+    })
+}
@@ -0,0 +1,1804 @@
+use {
+    crate::{
+        damage::{Damage, Rect},
+        dmabuf::{self, DRM_FORMAT_MOD_INVALID},
+        error::Error,
+        failure::{Action, FailurePolicy},
+        monotonic_now, pixels,
+        pool::{Buffer, BufferFormat, BufferPool, ShmStorage},
+        select::{Pattern, Pick, ToplevelSelector},
+    },
+    drm::node::NodeType,
+    gbm::BufferObjectFlags,
+    indexmap::IndexMap,
+    memfile::{MemFile, Seal},
+    std::{
+        collections::{HashMap, VecDeque},
+        fmt::{self, Display, Formatter},
+        fs::File,
+        io, mem,
+        os::fd::{AsFd, AsRawFd},
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+        time::Duration,
+    },
+    wayland_backend::client::{ObjectId, WaylandError},
+    wayland_client::{
+        delegate_noop, event_created_child,
+        protocol::{
+            wl_buffer::{self, WlBuffer},
+            wl_callback::{self, WlCallback},
+            wl_output::{self, Transform, WlOutput},
+            wl_pointer::WlPointer,
+            wl_registry::{self, WlRegistry},
+            wl_seat::{self, WlSeat},
+            wl_shm::{self, Format, WlShm},
+            wl_shm_pool::WlShmPool,
+        },
+        Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle, WEnum,
+    },
+    wayland_protocols::{
+        ext::{
+            foreign_toplevel_list::v1::client::{
+                ext_foreign_toplevel_handle_v1::{self, ExtForeignToplevelHandleV1},
+                ext_foreign_toplevel_list_v1::{
+                    self, ExtForeignToplevelListV1, EVT_TOPLEVEL_OPCODE,
+                },
+            },
+            image_capture_source::v1::client::{
+                ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+                ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+                ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+            },
+            image_copy_capture::v1::client::{
+                ext_image_copy_capture_cursor_session_v1::{
+                    self, ExtImageCopyCaptureCursorSessionV1,
+                },
+                ext_image_copy_capture_frame_v1::{
+                    self, ExtImageCopyCaptureFrameV1, FailureReason,
+                },
+                ext_image_copy_capture_manager_v1::{ExtImageCopyCaptureManagerV1, Options},
+                ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+            },
+        },
+        wp::linux_dmabuf::zv1::client::{
+            zwp_linux_buffer_params_v1::{Flags, ZwpLinuxBufferParamsV1},
+            zwp_linux_dmabuf_v1::{self, ZwpLinuxDmabufV1},
+        },
+        xdg::xdg_output::zv1::client::{
+            zxdg_output_manager_v1::ZxdgOutputManagerV1,
+            zxdg_output_v1::{self, ZxdgOutputV1},
+        },
+    },
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SessionId(u64);
+
+/// What a session captures.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The output with this name.
+    Output(String),
+    /// The toplevel with this foreign toplevel identifier.
+    Toplevel(String),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BufferType {
+    Shm,
+    Dmabuf,
+}
+
+/// When a session captures the next frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pacing {
+    /// As soon as a buffer is available.
+    Unlimited,
+    /// At most this many frames per second. Must be finite and positive.
+    MaxFps(f64),
+    /// Only after [`CaptureClient::request_frame`].
+    OnDemand,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    /// Paint the cursor into the frames. Ignored for cursor sessions.
+    pub paint_cursors: bool,
+    pub buffer_type: BufferType,
+    /// Use this shm format instead of picking one from the supported formats.
+    pub shm_format: Option<Format>,
+    /// Use this dmabuf format instead of picking one from the supported formats.
+    pub dmabuf_format: Option<gbm::Format>,
+    /// Maximum number of buffers.
+    pub buffers: usize,
+    /// Only use formats that can also be attached to a surface.
+    pub displayable: bool,
+    /// Re-create the session when the compositor stops it.
+    pub restart: bool,
+    /// Give up after this many consecutive failed frames or restarts.
+    pub max_retries: u32,
+    pub pacing: Pacing,
+    /// Report a session that is stopped before its first frame with
+    /// [`CaptureEvent::Stopped`] instead of failing with [`Error::CaptureDenied`].
+    pub allow_early_stop: bool,
+    /// Keep finished frames until the compositor has processed a later request so that
+    /// events sent after ready or failed are reported as [`CaptureEvent::Violation`].
+    pub check: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            paint_cursors: true,
+            buffer_type: BufferType::Shm,
+            shm_format: None,
+            dmabuf_format: None,
+            buffers: 3,
+            displayable: false,
+            restart: false,
+            max_retries: 5,
+            pacing: Pacing::Unlimited,
+            allow_early_stop: false,
+            check: false,
+        }
+    }
+}
+
+/// A captured frame.
+///
+/// The buffer belongs to the consumer until the compositor releases it after it has
+/// been attached to a surface or until [`CaptureClient::release`] is called. The
+/// session stalls once all of its buffers are held.
+pub struct Frame {
+    pub session: SessionId,
+    pub buffer_id: u64,
+    pub buffer: WlBuffer,
+    pub size: (i32, i32),
+    pub format: BufferFormat,
+    pub transform: Transform,
+    /// The region that changed since the previous frame of the session.
+    pub damage: Damage,
+    pub capture_time: Duration,
+    pub ready_time: Duration,
+    pub presentation_time: Option<Duration>,
+}
+
+pub enum CaptureEvent {
+    Frame(Frame),
+    FrameFailed {
+        session: SessionId,
+        reason: WEnum<FailureReason>,
+    },
+    CursorEnter(SessionId),
+    CursorLeave(SessionId),
+    CursorPosition {
+        session: SessionId,
+        x: i32,
+        y: i32,
+    },
+    CursorHotspot {
+        session: SessionId,
+        x: i32,
+        y: i32,
+    },
+    /// The compositor stopped the session and it will not produce any more frames.
+    Stopped(SessionId),
+    OutputAdded(Output),
+    /// The properties of an output changed. Sent once per atomic batch of changes.
+    OutputChanged(Output),
+    OutputRemoved(Output),
+    ToplevelAdded(ForeignToplevel),
+    /// The properties of a toplevel changed. Sent once per atomic batch of changes.
+    ToplevelChanged(ForeignToplevel),
+    ToplevelClosed(ForeignToplevel),
+    /// The compositor sent an event that the specification does not allow.
+    Violation(Violation),
+}
+
+/// An ext-image-copy-capture-v1 event that breaks a rule of the specification.
+///
+/// Events that arrive after the client has destroyed the object cannot be seen, see
+/// [`SessionOptions::check`].
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub session: SessionId,
+    pub rule: &'static str,
+    /// The offending event and the object that received it.
+    pub event: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.rule, self.event)
+    }
+}
+
+#[derive(Clone)]
+pub struct Output {
+    output: WlOutput,
+    xdg_output: Option<ZxdgOutputV1>,
+    global_name: u32,
+    announced: bool,
+    pub name: String,
+    pub description: String,
+    pub make: String,
+    pub model: String,
+    /// Position in the global compositor space.
+    pub position: (i32, i32),
+    /// Physical size in millimeters.
+    pub physical_size: (i32, i32),
+    pub transform: Transform,
+    /// The current mode.
+    pub mode: Option<OutputMode>,
+    pub scale: i32,
+    /// Position and size in the global compositor space from xdg-output.
+    pub logical_position: Option<(i32, i32)>,
+    pub logical_size: Option<(i32, i32)>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutputMode {
+    pub width: i32,
+    pub height: i32,
+    /// Refresh rate in mHz.
+    pub refresh: i32,
+}
+
+impl Output {
+    fn new(output: WlOutput, global_name: u32) -> Self {
+        Self {
+            output,
+            xdg_output: None,
+            global_name,
+            announced: false,
+            name: "".to_string(),
+            description: "".to_string(),
+            make: "".to_string(),
+            model: "".to_string(),
+            position: (0, 0),
+            physical_size: (0, 0),
+            transform: Transform::Normal,
+            mode: None,
+            scale: 1,
+            logical_position: None,
+            logical_size: None,
+        }
+    }
+
+    /// Returns whether the connector name, description, make or model matches.
+    pub fn matches(&self, pattern: &Pattern) -> bool {
+        [&self.name, &self.description, &self.make, &self.model]
+            .iter()
+            .any(|s| pattern.matches(s))
+    }
+}
+
+#[derive(Clone)]
+pub struct ForeignToplevel {
+    handle: ExtForeignToplevelHandleV1,
+    seq: u64,
+    pending: PendingToplevel,
+    announced: bool,
+    pub id: String,
+    pub title: String,
+    pub app_id: String,
+}
+
+/// Toplevel properties that take effect with the next `done` event.
+#[derive(Clone, Default)]
+struct PendingToplevel {
+    id: Option<String>,
+    title: Option<String>,
+    app_id: Option<String>,
+}
+
+/// A connection that captures outputs and toplevels with ext-image-copy-capture-v1.
+///
+/// The client has its own event queue. Consumers that create their own objects on the
+/// same connection dispatch this queue with [`CaptureClient::dispatch_pending`] after
+/// reading from the connection with [`CaptureClient::read_events`], which also wakes up
+/// for paced captures and retries, and then drain [`CaptureClient::next_event`].
+pub struct CaptureClient {
+    conn: Connection,
+    queue: EventQueue<State>,
+    qh: QueueHandle<State>,
+    state: State,
+}
+
+pub(crate) struct State {
+    wl_shm: Option<WlShm>,
+    wl_shm_formats: Vec<Format>,
+    zwp_linux_dmabuf_v1: Option<ZwpLinuxDmabufV1>,
+    zwp_linux_dmabuf_formats: Vec<u32>,
+    wl_seat: Option<WlSeat>,
+    pointer_capability: bool,
+    ext_output_image_capture_source_manager_v1: Option<ExtOutputImageCaptureSourceManagerV1>,
+    ext_foreign_toplevel_image_capture_source_manager_v1:
+        Option<ExtForeignToplevelImageCaptureSourceManagerV1>,
+    ext_foreign_toplevel_list_v1: Option<ExtForeignToplevelListV1>,
+    ext_image_copy_capture_manager_v1: Option<ExtImageCopyCaptureManagerV1>,
+    zxdg_output_manager_v1: Option<ZxdgOutputManagerV1>,
+    outputs: HashMap<ObjectId, Output>,
+    foreign_toplevels: HashMap<ObjectId, ForeignToplevel>,
+    next_toplevel_seq: u64,
+    sessions: HashMap<SessionId, Session>,
+    next_session_id: u64,
+    next_buffer_id: u64,
+    gbm: Option<gbm::Device<File>>,
+    events: VecDeque<CaptureEvent>,
+    error: Option<Error>,
+}
+
+struct Session {
+    source: Source,
+    options: SessionOptions,
+    session: ExtImageCopyCaptureSessionV1,
+    cursor: Option<CursorSession>,
+    frame: Option<ExtImageCopyCaptureFrameV1>,
+    /// A finished frame that is kept until a sync request returns, see
+    /// [`SessionOptions::check`].
+    retired_frame: Option<ExtImageCopyCaptureFrameV1>,
+    size: (i32, i32),
+    shm_formats: Vec<Format>,
+    pending_shm_formats: Vec<Format>,
+    dmabuf_formats: IndexMap<u32, Vec<u64>>,
+    pending_dmabuf_formats: IndexMap<u32, Vec<u64>>,
+    renegotiating: bool,
+    /// Buffer constraints arrived while a frame was in flight.
+    new_constraints: bool,
+    pool: BufferPool,
+    failures: FailurePolicy,
+    captured: bool,
+    /// No frame is captured before this time.
+    capture_at: Option<Duration>,
+    /// The stopped session is re-created at this time.
+    restart_at: Option<Duration>,
+    last_capture: Option<Duration>,
+    requested: bool,
+    stopped: bool,
+    /// Events received in the current batch of buffer constraints.
+    pending_size: bool,
+    pending_dmabuf_device: bool,
+    last_presentation_time: Option<Duration>,
+}
+
+struct CursorSession {
+    session: ExtImageCopyCaptureCursorSessionV1,
+    pointer: WlPointer,
+}
+
+struct FrameData {
+    session: SessionId,
+    buffer: u64,
+    /// Set by the ready and failed events.
+    finished: AtomicBool,
+}
+
+impl CaptureClient {
+    pub fn connect() -> Result<Self, Error> {
+        let conn = Connection::connect_to_env().map_err(Error::Connect)?;
+        Self::new(conn)
+    }
+
+    pub fn new(conn: Connection) -> Result<Self, Error> {
+        let mut queue = conn.new_event_queue();
+        let qh = queue.handle();
+        conn.display().get_registry(&qh, ());
+        let mut state = State {
+            wl_shm: None,
+            wl_shm_formats: vec![Format::Argb8888, Format::Xrgb8888],
+            zwp_linux_dmabuf_v1: None,
+            zwp_linux_dmabuf_formats: vec![],
+            wl_seat: None,
+            pointer_capability: false,
+            ext_output_image_capture_source_manager_v1: None,
+            ext_foreign_toplevel_image_capture_source_manager_v1: None,
+            ext_foreign_toplevel_list_v1: None,
+            ext_image_copy_capture_manager_v1: None,
+            zxdg_output_manager_v1: None,
+            outputs: Default::default(),
+            foreign_toplevels: Default::default(),
+            next_toplevel_seq: 0,
+            sessions: Default::default(),
+            next_session_id: 0,
+            next_buffer_id: 0,
+            gbm: None,
+            events: Default::default(),
+            error: None,
+        };
+        // The first roundtrip binds the globals, the second one receives their
+        // initial state.
+        queue.roundtrip(&mut state).map_err(Error::Protocol)?;
+        queue.roundtrip(&mut state).map_err(Error::Protocol)?;
+        Ok(Self {
+            conn,
+            queue,
+            qh,
+            state,
+        })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Returns the interface names of the capture source managers the compositor
+    /// supports.
+    pub fn source_managers(&self) -> Vec<&'static str> {
+        let mut managers = vec![];
+        if self
+            .state
+            .ext_output_image_capture_source_manager_v1
+            .is_some()
+        {
+            managers.push("ext_output_image_capture_source_manager_v1");
+        }
+        if self
+            .state
+            .ext_foreign_toplevel_image_capture_source_manager_v1
+            .is_some()
+        {
+            managers.push("ext_foreign_toplevel_image_capture_source_manager_v1");
+        }
+        managers
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = &Output> {
+        self.state.outputs.values().filter(|o| o.announced)
+    }
+
+    /// Returns the output whose name matches the pattern exactly or, failing that, the
+    /// only output whose connector name, description, make or model matches it.
+    pub fn find_output(&self, pattern: &Pattern) -> Result<&Output, Error> {
+        if let Some(o) = self.outputs().find(|o| o.name == pattern.as_str()) {
+            return Ok(o);
+        }
+        let mut matches: Vec<_> = self.outputs().filter(|o| o.matches(pattern)).collect();
+        matches.sort_by_cached_key(|o| &o.name);
+        match &matches[..] {
+            [] => Err(Error::UnknownOutput(pattern.as_str().to_string())),
+            [o] => Ok(o),
+            _ => Err(Error::AmbiguousOutput(
+                matches.iter().map(|o| o.name.clone()).collect(),
+            )),
+        }
+    }
+
+    pub fn toplevels(&self) -> impl Iterator<Item = &ForeignToplevel> {
+        self.state
+            .foreign_toplevels
+            .values()
+            .filter(|t| t.announced)
+    }
+
+    /// Returns the toplevel that matches the selector, using `pick` if there are
+    /// several.
+    pub fn find_toplevel(
+        &self,
+        selector: &ToplevelSelector,
+        pick: Pick,
+    ) -> Result<&ForeignToplevel, Error> {
+        let mut matches: Vec<_> = self.toplevels().filter(|t| selector.matches(t)).collect();
+        matches.sort_by_key(|t| t.seq);
+        match (pick, &matches[..]) {
+            (_, []) => Err(Error::UnknownToplevel(selector.to_string())),
+            (_, [t]) | (Pick::First, [t, ..]) | (Pick::Newest, [.., t]) => Ok(t),
+            (Pick::Error, _) => Err(Error::AmbiguousToplevel(
+                matches.iter().map(|t| t.id.clone()).collect(),
+            )),
+        }
+    }
+
+    pub fn create_session(
+        &mut self,
+        source: &Source,
+        options: SessionOptions,
+    ) -> Result<SessionId, Error> {
+        self.insert_session(source, options, false)
+    }
+
+    /// Creates a session that captures the cursor image of the first seat's pointer.
+    pub fn create_cursor_session(
+        &mut self,
+        source: &Source,
+        options: SessionOptions,
+    ) -> Result<SessionId, Error> {
+        self.insert_session(source, options, true)
+    }
+
+    fn insert_session(
+        &mut self,
+        source: &Source,
+        options: SessionOptions,
+        cursor: bool,
+    ) -> Result<SessionId, Error> {
+        if let Pacing::MaxFps(fps) = options.pacing {
+            if !fps.is_finite() || fps <= 0.0 {
+                return Err(Error::InvalidOptions(format!(
+                    "the frame rate {fps} is not a positive number"
+                )));
+            }
+        }
+        let id = SessionId(self.state.next_session_id);
+        let session = self
+            .state
+            .create_session(id, source, options, cursor, &self.qh)?;
+        self.state.next_session_id += 1;
+        self.state.sessions.insert(id, session);
+        Ok(id)
+    }
+
+    pub fn destroy_session(&mut self, id: SessionId) {
+        if let Some(session) = self.state.sessions.remove(&id) {
+            session.destroy();
+        }
+    }
+
+    /// Returns the buffer of a frame to its session.
+    pub fn release(&mut self, frame: &Frame) {
+        self.state.release(frame.buffer_id, &self.qh);
+    }
+
+    /// Reads the contents of an shm frame as RGBA8.
+    pub fn read_rgba8(&self, frame: &Frame) -> Result<Vec<u8>, String> {
+        self.state
+            .sessions
+            .get(&frame.session)
+            .and_then(|s| s.pool.get(frame.buffer_id))
+            .ok_or_else(|| "the buffer has been destroyed".to_string())?
+            .read_rgba8()
+    }
+
+    pub fn failures(&self, id: SessionId) -> Option<&FailurePolicy> {
+        self.state.sessions.get(&id).map(|s| &s.failures)
+    }
+
+    /// Returns the formats of the session's buffer type that the compositor offers and
+    /// buffers can be allocated in, or `None` before the buffer constraints have arrived.
+    pub fn buffer_formats(&self, id: SessionId) -> Option<Vec<BufferFormat>> {
+        let session = self.state.sessions.get(&id)?;
+        if session.shm_formats.is_empty() && session.dmabuf_formats.is_empty() {
+            return None;
+        }
+        let formats = match session.options.buffer_type {
+            BufferType::Shm => session
+                .shm_formats
+                .iter()
+                .filter(|&&f| pixels::shm_format(f).is_some())
+                .map(|&f| BufferFormat::Shm(f))
+                .collect(),
+            BufferType::Dmabuf => session
+                .dmabuf_formats
+                .keys()
+                .filter_map(|&f| gbm::Format::try_from(f).ok())
+                .map(BufferFormat::Dmabuf)
+                .collect(),
+        };
+        Some(formats)
+    }
+
+    /// Captures a frame as soon as a buffer is available. Only needed with
+    /// [`Pacing::OnDemand`].
+    pub fn request_frame(&mut self, id: SessionId) {
+        if let Some(session) = self.state.sessions.get_mut(&id) {
+            session.requested = true;
+            self.state.capture_frame(id, &self.qh);
+        }
+    }
+
+    /// Returns the time at which the next paced capture, retry or restart is due.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.state
+            .sessions
+            .values()
+            .flat_map(|s| [s.capture_at, s.restart_at])
+            .flatten()
+            .min()
+    }
+
+    /// Dispatches the queued events and runs the captures and restarts that are due.
+    pub fn dispatch_pending(&mut self) -> Result<(), Error> {
+        self.queue
+            .dispatch_pending(&mut self.state)
+            .map_err(Error::Protocol)?;
+        self.state.run_timers(&self.qh);
+        self.take_error()
+    }
+
+    /// Flushes the connection and blocks until events have been read from it, the
+    /// next deadline or `until` has passed or a signal has interrupted the wait.
+    pub fn read_events(&self, until: Option<Duration>) -> Result<(), Error> {
+        let err = |e| Error::Protocol(DispatchError::Backend(e));
+        self.conn.flush().map_err(err)?;
+        let Some(guard) = self.conn.prepare_read() else {
+            return Ok(());
+        };
+        let deadline = match (self.next_deadline(), until) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let timeout = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_sub(monotonic_now());
+                timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+        let mut fd = libc::pollfd {
+            fd: guard.connection_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, timeout) } {
+            0 => return Ok(()),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(()),
+                    _ => Err(err(WaylandError::Io(e))),
+                };
+            }
+            _ => {}
+        }
+        match guard.read() {
+            Ok(_) => Ok(()),
+            Err(WaylandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(err(e)),
+        }
+    }
+
+    /// Blocks until events have been dispatched or a deadline has passed.
+    pub fn blocking_dispatch(&mut self) -> Result<(), Error> {
+        self.dispatch_pending()?;
+        self.read_events(None)?;
+        self.dispatch_pending()
+    }
+
+    fn take_error(&mut self) -> Result<(), Error> {
+        match self.state.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn next_event(&mut self) -> Option<CaptureEvent> {
+        self.state.events.pop_front()
+    }
+
+    /// Dispatches until an event is available.
+    pub fn wait_event(&mut self) -> Result<CaptureEvent, Error> {
+        loop {
+            if let Some(event) = self.next_event() {
+                return Ok(event);
+            }
+            self.blocking_dispatch()?;
+        }
+    }
+}
+
+fn global<'a, T>(global: &'a Option<T>, name: &'static str) -> Result<&'a T, Error> {
+    global.as_ref().ok_or(Error::MissingGlobal(name))
+}
+
+impl Session {
+    fn new(
+        source: Source,
+        options: SessionOptions,
+        session: ExtImageCopyCaptureSessionV1,
+        cursor: Option<CursorSession>,
+    ) -> Self {
+        Self {
+            pool: BufferPool::new(options.buffers),
+            failures: FailurePolicy::new(options.max_retries),
+            source,
+            options,
+            session,
+            cursor,
+            frame: None,
+            retired_frame: None,
+            size: (0, 0),
+            shm_formats: vec![],
+            pending_shm_formats: vec![],
+            dmabuf_formats: Default::default(),
+            pending_dmabuf_formats: Default::default(),
+            renegotiating: false,
+            new_constraints: false,
+            captured: false,
+            capture_at: None,
+            restart_at: None,
+            last_capture: None,
+            requested: false,
+            stopped: false,
+            pending_size: false,
+            pending_dmabuf_device: false,
+            last_presentation_time: None,
+        }
+    }
+
+    fn destroy(mut self) {
+        for frame in [self.frame.take(), self.retired_frame.take()]
+            .into_iter()
+            .flatten()
+        {
+            frame.destroy();
+        }
+        self.pool.clear();
+        self.session.destroy();
+        if let Some(cursor) = self.cursor {
+            cursor.session.destroy();
+            if cursor.pointer.version() >= 3 {
+                cursor.pointer.release();
+            }
+        }
+    }
+
+    /// Destroys a frame after its ready or failed event.
+    ///
+    /// With [`SessionOptions::check`] the frame lives on until the compositor has answered
+    /// a sync request, so that events it sent after the last one can still be reported.
+    /// A session can only have one frame, so no new frame is captured in the meantime.
+    fn retire_frame(
+        &mut self,
+        frame: &ExtImageCopyCaptureFrameV1,
+        conn: &Connection,
+        qh: &QueueHandle<State>,
+    ) {
+        if self.options.check {
+            conn.display().sync(qh, frame.clone());
+            self.retired_frame = Some(frame.clone());
+        } else {
+            frame.destroy();
+        }
+    }
+}
+
+impl State {
+    /// Stops dispatching and reports the error to the consumer.
+    fn fail(&mut self, error: Error) {
+        self.error.get_or_insert(error);
+    }
+
+    fn create_source(
+        &self,
+        source: &Source,
+        qh: &QueueHandle<Self>,
+    ) -> Result<ExtImageCaptureSourceV1, Error> {
+        match source {
+            Source::Output(n) => {
+                let Some(o) = self.outputs.values().find(|o| &o.name == n) else {
+                    return Err(Error::UnknownOutput(n.clone()));
+                };
+                let oicsm = global(
+                    &self.ext_output_image_capture_source_manager_v1,
+                    "ext_output_image_capture_source_manager_v1",
+                )?;
+                Ok(oicsm.create_source(&o.output, qh, ()))
+            }
+            Source::Toplevel(id) => {
+                let Some(o) = self.foreign_toplevels.values().find(|o| &o.id == id) else {
+                    return Err(Error::UnknownToplevel(id.clone()));
+                };
+                let fticsm = global(
+                    &self.ext_foreign_toplevel_image_capture_source_manager_v1,
+                    "ext_foreign_toplevel_image_capture_source_manager_v1",
+                )?;
+                Ok(fticsm.create_source(&o.handle, qh, ()))
+            }
+        }
+    }
+
+    fn create_session(
+        &self,
+        id: SessionId,
+        source: &Source,
+        options: SessionOptions,
+        cursor: bool,
+        qh: &QueueHandle<Self>,
+    ) -> Result<Session, Error> {
+        let iccm = global(
+            &self.ext_image_copy_capture_manager_v1,
+            "ext_image_copy_capture_manager_v1",
+        )?;
+        if cursor && (self.wl_seat.is_none() || !self.pointer_capability) {
+            return Err(Error::NoPointer);
+        }
+        let capture_source = self.create_source(source, qh)?;
+        let (session, cursor) = match &self.wl_seat {
+            Some(seat) if cursor => {
+                let pointer = seat.get_pointer(qh, ());
+                let session = iccm.create_pointer_cursor_session(&capture_source, &pointer, qh, id);
+                let capture = session.get_capture_session(qh, id);
+                let cursor = CursorSession { session, pointer };
+                (capture, Some(cursor))
+            }
+            _ => {
+                let flags = match options.paint_cursors {
+                    true => Options::PaintCursors,
+                    false => Options::empty(),
+                };
+                (iccm.create_session(&capture_source, flags, qh, id), None)
+            }
+        };
+        capture_source.destroy();
+        Ok(Session::new(source.clone(), options, session, cursor))
+    }
+
+    fn restart_session(&mut self, id: SessionId, qh: &QueueHandle<Self>) -> Result<(), Error> {
+        let Some(old) = self.sessions.get(&id) else {
+            return Ok(());
+        };
+        let source = old.source.clone();
+        let cursor = old.cursor.is_some();
+        let mut session = self.create_session(id, &source, old.options.clone(), cursor, qh)?;
+        let mut old = self.sessions.remove(&id).unwrap();
+        session.failures = mem::take(&mut old.failures);
+        session.captured = old.captured;
+        old.destroy();
+        self.sessions.insert(id, session);
+        Ok(())
+    }
+
+    /// Re-creates a stopped session or reports it as stopped if its source is gone.
+    fn restart(&mut self, id: SessionId, qh: &QueueHandle<Self>) {
+        match self.restart_session(id, qh) {
+            Ok(()) => {}
+            // The output or toplevel has gone away.
+            Err(Error::UnknownOutput(_) | Error::UnknownToplevel(_)) => {
+                self.events.push_back(CaptureEvent::Stopped(id));
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn run_timers(&mut self, qh: &QueueHandle<Self>) {
+        let now = monotonic_now();
+        let due = |t: Option<Duration>| t.is_some_and(|t| t <= now);
+        let ids: Vec<_> = self.sessions.keys().copied().collect();
+        for id in ids {
+            let Some(session) = self.sessions.get_mut(&id) else {
+                continue;
+            };
+            if due(session.restart_at) {
+                session.restart_at = None;
+                self.restart(id, qh);
+            } else if due(session.capture_at) {
+                session.capture_at = None;
+                self.capture_frame(id, qh);
+            }
+        }
+    }
+
+    fn release(&mut self, buffer: u64, qh: &QueueHandle<Self>) {
+        let id = self
+            .sessions
+            .iter_mut()
+            .find_map(|(id, s)| s.pool.release(buffer).then_some(*id));
+        if let Some(id) = id {
+            self.capture_frame(id, qh);
+        }
+    }
+
+    fn capture_frame(&mut self, id: SessionId, qh: &QueueHandle<Self>) {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return;
+        };
+        if session.frame.is_some()
+            || session.retired_frame.is_some()
+            || session.renegotiating
+            || session.restart_at.is_some()
+            || session.stopped
+        {
+            return;
+        }
+        let now = monotonic_now();
+        if session.capture_at.is_some_and(|t| t > now) {
+            return;
+        }
+        match session.options.pacing {
+            Pacing::Unlimited => {}
+            Pacing::MaxFps(fps) => {
+                let next = session
+                    .last_capture
+                    .map(|t| t + Duration::from_secs_f64(1.0 / fps));
+                if let Some(next) = next.filter(|&t| t > now) {
+                    session.capture_at = Some(next);
+                    return;
+                }
+            }
+            Pacing::OnDemand if !session.requested => return,
+            Pacing::OnDemand => {}
+        }
+        let session = &self.sessions[&id];
+        let size = session.size;
+        if size.0 == 0 || size.1 == 0 {
+            return;
+        }
+        let mut modifiers = vec![];
+        let format = match session.options.buffer_type {
+            BufferType::Dmabuf => match self.select_dmabuf_format(session) {
+                Some(format) => {
+                    modifiers = session.dmabuf_formats[&(format as u32)].clone();
+                    BufferFormat::Dmabuf(format)
+                }
+                None => {
+                    let offered = session
+                        .dmabuf_formats
+                        .keys()
+                        .map(|&f| match gbm::Format::try_from(f) {
+                            Ok(f) => format!("{f:?}"),
+                            _ => format!("{f:#x}"),
+                        })
+                        .collect();
+                    self.fail(Error::UnsupportedFormat {
+                        kind: "dmabuf",
+                        offered,
+                    });
+                    return;
+                }
+            },
+            BufferType::Shm => match self.select_shm_format(session) {
+                Some(format) => BufferFormat::Shm(format),
+                None => {
+                    let offered = session
+                        .shm_formats
+                        .iter()
+                        .map(|f| format!("{f:?}"))
+                        .collect();
+                    self.fail(Error::UnsupportedFormat {
+                        kind: "shm",
+                        offered,
+                    });
+                    return;
+                }
+            },
+        };
+        let session = self.sessions.get_mut(&id).unwrap();
+        session.pool.retain_compatible(size, format);
+        if session.pool.needs_allocation() {
+            let buffer = match self.create_buffer(size, format, &modifiers, qh) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    self.fail(e);
+                    return;
+                }
+            };
+            self.sessions.get_mut(&id).unwrap().pool.insert(buffer);
+        }
+        let session = self.sessions.get_mut(&id).unwrap();
+        let Some(b) = session.pool.acquire() else {
+            // Every buffer is still held by the consumer. The capture resumes once one
+            // of them is released.
+            return;
+        };
+        b.presentation_time = None;
+        b.transform = Transform::Normal;
+        b.frame_damage.clear();
+        let frame = session.session.create_frame(
+            qh,
+            FrameData {
+                session: id,
+                buffer: b.id,
+                finished: AtomicBool::new(false),
+            },
+        );
+        frame.attach_buffer(&b.buffer);
+        for rect in b.damage.rects() {
+            frame.damage_buffer(rect.x, rect.y, rect.width, rect.height);
+        }
+        b.capture_time = now;
+        frame.capture();
+        session.frame = Some(frame);
+        session.capture_at = None;
+        session.last_capture = Some(now);
+        session.requested = false;
+    }
+
+    fn select_shm_format(&self, session: &Session) -> Option<Format> {
+        // Buffers that are displayed must also be supported by wl_shm.
+        let displayable = session.options.displayable;
+        let usable = |f: &Format| {
+            session.shm_formats.contains(f)
+                && (!displayable || self.wl_shm_formats.contains(f))
+                && pixels::shm_format(*f).is_some()
+        };
+        match session.options.shm_format {
+            Some(format) => usable(&format).then_some(format),
+            None => pixels::SHM_FORMATS.iter().map(|f| f.format).find(usable),
+        }
+    }
+
+    fn select_dmabuf_format(&self, session: &Session) -> Option<gbm::Format> {
+        // If the compositor did not list its formats, let it reject the buffer instead.
+        let displayable = session.options.displayable && !self.zwp_linux_dmabuf_formats.is_empty();
+        let usable = |f: &gbm::Format| {
+            let f = *f as u32;
+            session.dmabuf_formats.contains_key(&f)
+                && (!displayable || self.zwp_linux_dmabuf_formats.contains(&f))
+        };
+        match session.options.dmabuf_format {
+            Some(format) => usable(&format).then_some(format),
+            None => dmabuf::DMABUF_FORMATS.iter().map(|f| f.format).find(usable),
+        }
+    }
+
+    fn create_buffer(
+        &mut self,
+        size: (i32, i32),
+        format: BufferFormat,
+        modifiers: &[u64],
+        qh: &QueueHandle<Self>,
+    ) -> Result<Buffer, Error> {
+        let mut bo_opt = None;
+        let mut shm_opt = None;
+        let buffer = match format {
+            BufferFormat::Shm(format) => {
+                let bpp = pixels::shm_format(format)
+                    .ok_or_else(|| Error::Allocation(format!("unsupported shm format {format:?}")))?
+                    .bpp;
+                let memfile = MemFile::create_sealable("wl_shm").map_err(Error::allocation)?;
+                let stride = size.0 * bpp;
+                let len = stride * size.1;
+                memfile.set_len(len as _).map_err(Error::allocation)?;
+                memfile.add_seal(Seal::Shrink).map_err(Error::allocation)?;
+                let shm = global(&self.wl_shm, "wl_shm")?;
+                let pool = shm.create_pool(memfile.as_fd(), len, qh, ());
+                let buffer =
+                    pool.create_buffer(0, size.0, size.1, stride, format, qh, self.next_buffer_id);
+                pool.destroy();
+                shm_opt = Some(ShmStorage { memfile, stride });
+                buffer
+            }
+            BufferFormat::Dmabuf(format) => {
+                let Some(gbm) = &self.gbm else {
+                    return Err(Error::NoDmabufDevice);
+                };
+                let implicit = modifiers.iter().all(|&m| m == DRM_FORMAT_MOD_INVALID);
+                let bo = if implicit {
+                    gbm.create_buffer_object::<()>(
+                        size.0 as _,
+                        size.1 as _,
+                        format,
+                        BufferObjectFlags::RENDERING,
+                    )
+                } else {
+                    gbm.create_buffer_object_with_modifiers2::<()>(
+                        size.0 as _,
+                        size.1 as _,
+                        format,
+                        modifiers
+                            .iter()
+                            .filter(|&&m| m != DRM_FORMAT_MOD_INVALID)
+                            .map(|&m| m.into()),
+                        BufferObjectFlags::RENDERING,
+                    )
+                }
+                .map_err(Error::allocation)?;
+                let dmabuf = global(&self.zwp_linux_dmabuf_v1, "zwp_linux_dmabuf_v1")?;
+                let params = dmabuf.create_params(qh, ());
+                let modifier: u64 = bo.modifier().map_err(Error::allocation)?.into();
+                for i in 0..bo.plane_count().map_err(Error::allocation)? {
+                    let fd = bo.fd_for_plane(i as _).map_err(Error::allocation)?;
+                    params.add(
+                        fd.as_fd(),
+                        i,
+                        bo.offset(i as _).map_err(Error::allocation)?,
+                        bo.stride_for_plane(i as _).map_err(Error::allocation)?,
+                        (modifier >> 32) as _,
+                        modifier as _,
+                    );
+                }
+                let buffer = params.create_immed(
+                    size.0,
+                    size.1,
+                    format as _,
+                    Flags::empty(),
+                    qh,
+                    self.next_buffer_id,
+                );
+                params.destroy();
+                bo_opt = Some(bo);
+                buffer
+            }
+        };
+        let b = Buffer {
+            id: self.next_buffer_id,
+            buffer,
+            free: true,
+            size,
+            format,
+            shm: shm_opt,
+            capture_time: Duration::ZERO,
+            presentation_time: None,
+            transform: Transform::Normal,
+            damage: Damage::full(size),
+            frame_damage: Damage::default(),
+            _bo_opt: bo_opt,
+        };
+        self.next_buffer_id += 1;
+        Ok(b)
+    }
+}
+
+fn open_gbm_device(dev: libc::dev_t) -> Result<gbm::Device<File>, Error> {
+    let path = drm::node::dev_path(dev, NodeType::Render).map_err(Error::allocation)?;
+    let fd = File::options()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .map_err(|e| Error::Allocation(format!("{}: {e}", path.display())))?;
+    gbm::Device::new(fd).map_err(Error::allocation)
+}
+
+impl Dispatch<WlRegistry, ()> for State {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::GlobalRemove { name } = event {
+            let Some(id) = state
+                .outputs
+                .values()
+                .find(|o| o.global_name == name)
+                .map(|o| o.output.id())
+            else {
+                return;
+            };
+            let output = state.outputs.remove(&id).unwrap();
+            if let Some(xdg_output) = &output.xdg_output {
+                xdg_output.destroy();
+            }
+            output.output.release();
+            if output.announced {
+                state.events.push_back(CaptureEvent::OutputRemoved(output));
+            }
+            return;
+        }
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            match &interface[..] {
+                "wl_shm" => {
+                    state.wl_shm = Some(registry.bind::<WlShm, _, _>(name, 1, qh, ()));
+                }
+                "wl_output" => {
+                    let o = registry.bind::<WlOutput, _, _>(name, 4, qh, ());
+                    let mut output = Output::new(o.clone(), name);
+                    if let Some(xom) = &state.zxdg_output_manager_v1 {
+                        output.xdg_output = Some(xom.get_xdg_output(&o, qh, o.id()));
+                    }
+                    state.outputs.insert(o.id(), output);
+                }
+                "zxdg_output_manager_v1" => {
+                    let version = version.min(3);
+                    let xom = registry.bind::<ZxdgOutputManagerV1, _, _>(name, version, qh, ());
+                    for output in state.outputs.values_mut() {
+                        let id = output.output.id();
+                        output.xdg_output = Some(xom.get_xdg_output(&output.output, qh, id));
+                    }
+                    state.zxdg_output_manager_v1 = Some(xom);
+                }
+                "ext_image_copy_capture_manager_v1" => {
+                    state.ext_image_copy_capture_manager_v1 =
+                        Some(registry.bind::<ExtImageCopyCaptureManagerV1, _, _>(name, 1, qh, ()));
+                }
+                "zwp_linux_dmabuf_v1" => {
+                    state.zwp_linux_dmabuf_v1 =
+                        Some(registry.bind::<ZwpLinuxDmabufV1, _, _>(name, 2, qh, ()));
+                }
+                "ext_output_image_capture_source_manager_v1" => {
+                    state.ext_output_image_capture_source_manager_v1 =
+                        Some(registry.bind::<ExtOutputImageCaptureSourceManagerV1, _, _>(
+                            name,
+                            1,
+                            qh,
+                            (),
+                        ));
+                }
+                "ext_foreign_toplevel_image_capture_source_manager_v1" => {
+                    state.ext_foreign_toplevel_image_capture_source_manager_v1 = Some(
+                        registry.bind::<ExtForeignToplevelImageCaptureSourceManagerV1, _, _>(
+                            name,
+                            1,
+                            qh,
+                            (),
+                        ),
+                    );
+                }
+                "wl_seat" if state.wl_seat.is_none() => {
+                    let version = version.min(5);
+                    state.wl_seat = Some(registry.bind::<WlSeat, _, _>(name, version, qh, ()));
+                }
+                "ext_foreign_toplevel_list_v1" => {
+                    state.ext_foreign_toplevel_list_v1 =
+                        Some(registry.bind::<ExtForeignToplevelListV1, _, _>(name, 1, qh, ()));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+delegate_noop!(State: ignore WlShmPool);
+delegate_noop!(State: ignore ExtImageCaptureSourceV1);
+delegate_noop!(State: ignore ExtOutputImageCaptureSourceManagerV1);
+delegate_noop!(State: ignore ExtForeignToplevelImageCaptureSourceManagerV1);
+delegate_noop!(State: ignore ExtImageCopyCaptureManagerV1);
+delegate_noop!(State: ignore ZwpLinuxBufferParamsV1);
+delegate_noop!(State: ignore WlPointer);
+delegate_noop!(State: ignore ZxdgOutputManagerV1);
+
+impl Dispatch<WlSeat, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlSeat,
+        event: wl_seat::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        {
+            state.pointer_capability = capabilities.contains(wl_seat::Capability::Pointer);
+        }
+    }
+}
+
+impl Dispatch<WlBuffer, u64> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlBuffer,
+        _: wl_buffer::Event,
+        id: &u64,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        state.release(*id, qh);
+    }
+}
+
+impl Dispatch<WlShm, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlShm,
+        event: wl_shm::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_shm::Event::Format {
+            format: WEnum::Value(format),
+        } = event
+        {
+            if !state.wl_shm_formats.contains(&format) {
+                state.wl_shm_formats.push(format);
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwpLinuxDmabufV1,
+        event: zwp_linux_dmabuf_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwp_linux_dmabuf_v1::Event::Format { format } = event {
+            state.zwp_linux_dmabuf_formats.push(format);
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, SessionId> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ExtImageCopyCaptureSessionV1,
+        event: ext_image_copy_capture_session_v1::Event,
+        id: &SessionId,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use ext_image_copy_capture_session_v1::Event;
+
+        let Some(session) = state.sessions.get_mut(id).filter(|s| &s.session == proxy) else {
+            return;
+        };
+        let mut violations = vec![];
+        match event {
+            _ if session.stopped => violations.push("event after stopped"),
+            Event::BufferSize { .. } => {
+                let repeated = mem::replace(&mut session.pending_size, true);
+                if repeated {
+                    violations.push("buffer_size sent twice before done");
+                }
+            }
+            Event::DmabufDevice { .. } => {
+                let repeated = mem::replace(&mut session.pending_dmabuf_device, true);
+                if repeated {
+                    violations.push("dmabuf_device sent twice before done");
+                }
+            }
+            Event::Done => {
+                let size = mem::take(&mut session.pending_size);
+                let dmabuf_device = mem::take(&mut session.pending_dmabuf_device);
+                if !size {
+                    violations.push("done without buffer_size");
+                }
+                if session.pending_shm_formats.is_empty()
+                    && session.pending_dmabuf_formats.is_empty()
+                {
+                    violations.push("done without shm_format or dmabuf_format");
+                }
+                if dmabuf_device && session.pending_dmabuf_formats.is_empty() {
+                    violations.push("dmabuf_device without dmabuf_format");
+                }
+            }
+            _ => {}
+        }
+        for rule in violations {
+            state.events.push_back(CaptureEvent::Violation(Violation {
+                session: *id,
+                rule,
+                event: format!("{}.{:?}", proxy.id(), event),
+            }));
+        }
+        if session.stopped {
+            return;
+        }
+        match event {
+            Event::BufferSize { width, height } => {
+                session.size = (width as _, height as _);
+            }
+            Event::DmabufDevice { device }
+                if session.options.buffer_type == BufferType::Dmabuf && state.gbm.is_none() =>
+            {
+                match open_gbm_device(bytemuck::pod_read_unaligned(&device)) {
+                    Ok(gbm) => state.gbm = Some(gbm),
+                    Err(e) => state.fail(e),
+                }
+            }
+            Event::DmabufFormat { format, modifiers } => {
+                let modifiers: Vec<u64> = bytemuck::pod_collect_to_vec(&modifiers);
+                session
+                    .pending_dmabuf_formats
+                    .entry(format)
+                    .or_default()
+                    .extend(modifiers);
+            }
+            Event::ShmFormat {
+                format: WEnum::Value(format),
+            } => {
+                session.pending_shm_formats.push(format);
+            }
+            Event::Stopped => {
+                session.stopped = true;
+                if session.options.restart {
+                    if let Some(delay) = session.failures.retry() {
+                        session.restart_at = Some(monotonic_now() + delay);
+                        return;
+                    }
+                }
+                if session.failures.exhausted() {
+                    let retries = session.failures.max_retries();
+                    state.fail(Error::CaptureFailed(retries));
+                } else if session.cursor.is_none()
+                    && !session.captured
+                    && !session.options.allow_early_stop
+                {
+                    state.fail(Error::CaptureDenied);
+                } else {
+                    state.events.push_back(CaptureEvent::Stopped(*id));
+                }
+            }
+            Event::Done => {
+                session.shm_formats = mem::take(&mut session.pending_shm_formats);
+                session.dmabuf_formats = mem::take(&mut session.pending_dmabuf_formats);
+                session.renegotiating = false;
+                session.new_constraints = session.frame.is_some();
+                state.capture_frame(*id, qh);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, FrameData> for State {
+    fn event(
+        state: &mut Self,
+        frame: &ExtImageCopyCaptureFrameV1,
+        event: ext_image_copy_capture_frame_v1::Event,
+        data: &FrameData,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use ext_image_copy_capture_frame_v1::Event;
+
+        let finished = data.finished.load(Relaxed);
+        if let Event::Ready | Event::Failed { .. } = event {
+            data.finished.store(true, Relaxed);
+        }
+        let session = state.sessions.get_mut(&data.session);
+        let Some((session, buffer, last_presentation_time)) = session.and_then(|s| {
+            let buffer = s.pool.get_mut(data.buffer)?;
+            Some((&mut s.frame, buffer, &mut s.last_presentation_time))
+        }) else {
+            // The frame belongs to a session or buffers that have since been destroyed.
+            if let Event::Ready | Event::Failed { .. } = event {
+                frame.destroy();
+            }
+            return;
+        };
+
+        let rule = match event {
+            _ if finished => Some("event after ready or failed"),
+            Event::Transform {
+                transform: WEnum::Unknown(_),
+            } => Some("unknown transform"),
+            Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } if x < 0
+                || y < 0
+                || width <= 0
+                || height <= 0
+                || x.saturating_add(width) > buffer.size.0
+                || y.saturating_add(height) > buffer.size.1 =>
+            {
+                Some("damage outside the buffer")
+            }
+            Event::PresentationTime { tv_nsec, .. } if tv_nsec >= 1_000_000_000 => {
+                Some("presentation_time with tv_nsec out of range")
+            }
+            Event::PresentationTime {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } if last_presentation_time.is_some_and(|t| {
+                let secs = ((tv_sec_hi as u64) << 32) | tv_sec_lo as u64;
+                Duration::new(secs, tv_nsec) < t
+            }) =>
+            {
+                Some("presentation_time went backwards")
+            }
+            Event::Failed {
+                reason: WEnum::Unknown(_),
+            } => Some("unknown failure reason"),
+            _ => None,
+        };
+        if let Some(rule) = rule {
+            state.events.push_back(CaptureEvent::Violation(Violation {
+                session: data.session,
+                rule,
+                event: format!("{}.{:?}", frame.id(), event),
+            }));
+        }
+        if finished {
+            return;
+        }
+
+        match event {
+            Event::Transform {
+                transform: WEnum::Value(transform),
+            } => {
+                buffer.transform = transform;
+            }
+            Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let bounds = Rect::new(0, 0, buffer.size.0, buffer.size.1);
+                let rect = Rect::new(x, y, width, height).intersect(&bounds);
+                buffer.frame_damage.add(rect);
+            }
+            Event::PresentationTime {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                let secs = ((tv_sec_hi as u64) << 32) | tv_sec_lo as u64;
+                buffer.presentation_time = Some(Duration::new(secs, tv_nsec));
+            }
+            Event::Ready => {
+                session.take();
+                buffer.free = false;
+                if buffer.presentation_time.is_some() {
+                    *last_presentation_time = buffer.presentation_time;
+                }
+                let session = state.sessions.get_mut(&data.session).unwrap();
+                session.retire_frame(frame, conn, qh);
+                session.captured = true;
+                session.new_constraints = false;
+                session.failures.frame_ready();
+                let damage = session.pool.accumulate_damage(data.buffer);
+                let buffer = session.pool.get(data.buffer).unwrap();
+                state.events.push_back(CaptureEvent::Frame(Frame {
+                    session: data.session,
+                    buffer_id: buffer.id,
+                    buffer: buffer.buffer.clone(),
+                    size: buffer.size,
+                    format: buffer.format,
+                    transform: buffer.transform,
+                    damage,
+                    capture_time: buffer.capture_time,
+                    ready_time: monotonic_now(),
+                    presentation_time: buffer.presentation_time,
+                }));
+                state.capture_frame(data.session, qh);
+            }
+            Event::Failed { reason } => {
+                buffer.frame_damage.clear();
+                session.take();
+                state.events.push_back(CaptureEvent::FrameFailed {
+                    session: data.session,
+                    reason,
+                });
+                let session = state.sessions.get_mut(&data.session).unwrap();
+                session.retire_frame(frame, conn, qh);
+                let new_constraints = mem::take(&mut session.new_constraints);
+                match session.failures.frame_failed(reason) {
+                    // The frame may have failed because of constraints that have already
+                    // arrived.
+                    Action::Renegotiate if new_constraints => {
+                        session.pool.clear();
+                        state.capture_frame(data.session, qh);
+                    }
+                    Action::Renegotiate => {
+                        session.pool.clear();
+                        session.renegotiating = true;
+                    }
+                    // Handled by the stopped event of the session.
+                    Action::Stop => {}
+                    Action::Retry(delay) => {
+                        session.capture_at = Some(monotonic_now() + delay);
+                    }
+                    Action::GiveUp => {
+                        let retries = session.failures.max_retries();
+                        state.fail(Error::CaptureFailed(retries));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlCallback, ExtImageCopyCaptureFrameV1> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlCallback,
+        event: wl_callback::Event,
+        frame: &ExtImageCopyCaptureFrameV1,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let wl_callback::Event::Done { .. } = event else {
+            return;
+        };
+        frame.destroy();
+        let Some(data) = frame.data::<FrameData>() else {
+            return;
+        };
+        // The session may have been re-created with a frame of its own in the meantime.
+        let Some(session) = state.sessions.get_mut(&data.session) else {
+            return;
+        };
+        if session.retired_frame.as_ref() == Some(frame) {
+            session.retired_frame = None;
+            state.capture_frame(data.session, qh);
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureCursorSessionV1, SessionId> for State {
+    fn event(
+        state: &mut Self,
+        _: &ExtImageCopyCaptureCursorSessionV1,
+        event: ext_image_copy_capture_cursor_session_v1::Event,
+        id: &SessionId,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use ext_image_copy_capture_cursor_session_v1::Event;
+
+        let session = *id;
+        let event = match event {
+            Event::Enter => CaptureEvent::CursorEnter(session),
+            Event::Leave => CaptureEvent::CursorLeave(session),
+            Event::Position { x, y } => CaptureEvent::CursorPosition { session, x, y },
+            Event::Hotspot { x, y } => CaptureEvent::CursorHotspot { session, x, y },
+            _ => return,
+        };
+        state.events.push_back(event);
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ExtForeignToplevelListV1,
+        _: ext_foreign_toplevel_list_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(State, ExtForeignToplevelListV1, [
+        EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtForeignToplevelHandleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        handle: &ExtForeignToplevelHandleV1,
+        event: ext_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use ext_foreign_toplevel_handle_v1::Event;
+
+        let tl = state
+            .foreign_toplevels
+            .entry(handle.id())
+            .or_insert_with(|| {
+                state.next_toplevel_seq += 1;
+                ForeignToplevel {
+                    handle: handle.clone(),
+                    seq: state.next_toplevel_seq,
+                    pending: Default::default(),
+                    announced: false,
+                    id: "".to_string(),
+                    title: "".to_string(),
+                    app_id: "".to_string(),
+                }
+            });
+
+        match event {
+            Event::Closed => {
+                let tl = state.foreign_toplevels.remove(&handle.id()).unwrap();
+                handle.destroy();
+                if tl.announced {
+                    state.events.push_back(CaptureEvent::ToplevelClosed(tl));
+                }
+            }
+            Event::Title { title } => {
+                tl.pending.title = Some(title);
+            }
+            Event::AppId { app_id } => {
+                tl.pending.app_id = Some(app_id);
+            }
+            Event::Identifier { identifier } => {
+                tl.pending.id = Some(identifier);
+            }
+            Event::Done => {
+                let pending = mem::take(&mut tl.pending);
+                if let Some(id) = pending.id {
+                    tl.id = id;
+                }
+                if let Some(title) = pending.title {
+                    tl.title = title;
+                }
+                if let Some(app_id) = pending.app_id {
+                    tl.app_id = app_id;
+                }
+                let event = match mem::replace(&mut tl.announced, true) {
+                    true => CaptureEvent::ToplevelChanged(tl.clone()),
+                    false => CaptureEvent::ToplevelAdded(tl.clone()),
+                };
+                state.events.push_back(event);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, ()> for State {
+    fn event(
+        state: &mut Self,
+        output: &WlOutput,
+        event: wl_output::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use wl_output::Event;
+
+        // Events can still arrive for an output whose global has been removed.
+        let Some(o) = state.outputs.get_mut(&output.id()) else {
+            return;
+        };
+
+        match event {
+            Event::Geometry {
+                x,
+                y,
+                physical_width,
+                physical_height,
+                make,
+                model,
+                transform,
+                ..
+            } => {
+                o.position = (x, y);
+                o.physical_size = (physical_width, physical_height);
+                o.make = make;
+                o.model = model;
+                if let WEnum::Value(transform) = transform {
+                    o.transform = transform;
+                }
+            }
+            Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                refresh,
+            } if flags.contains(wl_output::Mode::Current) => {
+                o.mode = Some(OutputMode {
+                    width,
+                    height,
+                    refresh,
+                });
+            }
+            Event::Scale { factor } => {
+                o.scale = factor;
+            }
+            Event::Name { name } => {
+                o.name = name;
+            }
+            Event::Description { description } => {
+                o.description = description;
+            }
+            Event::Done => {
+                let event = match mem::replace(&mut o.announced, true) {
+                    true => CaptureEvent::OutputChanged(o.clone()),
+                    false => CaptureEvent::OutputAdded(o.clone()),
+                };
+                state.events.push_back(event);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZxdgOutputV1, ObjectId> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZxdgOutputV1,
+        event: zxdg_output_v1::Event,
+        output: &ObjectId,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use zxdg_output_v1::Event;
+
+        let Some(o) = state.outputs.get_mut(output) else {
+            return;
+        };
+        match event {
+            Event::LogicalPosition { x, y } => {
+                o.logical_position = Some((x, y));
+            }
+            Event::LogicalSize { width, height } => {
+                o.logical_size = Some((width, height));
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,112 @@
+const MAX_RECTS: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    fn x2(&self) -> i32 {
+        self.x + self.width
+    }
+
+    fn y2(&self) -> i32 {
+        self.y + self.height
+    }
+
+    fn contains(&self, other: &Rect) -> bool {
+        self.x <= other.x && self.y <= other.y && self.x2() >= other.x2() && self.y2() >= other.y2()
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        Rect::new(
+            x,
+            y,
+            self.x2().min(other.x2()) - x,
+            self.y2().min(other.y2()) - y,
+        )
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.x2().max(other.x2()) - x,
+            self.y2().max(other.y2()) - y,
+        )
+    }
+}
+
+/// Parses a rectangle in the form `x,y,width,height`.
+pub fn parse_rect(s: &str) -> Result<Rect, String> {
+    let parts: Vec<_> = s.split(',').map(|p| p.trim().parse::<i32>()).collect();
+    match parts[..] {
+        [Ok(x), Ok(y), Ok(width), Ok(height)] if x >= 0 && y >= 0 && width > 0 && height > 0 => {
+            Ok(Rect::new(x, y, width, height))
+        }
+        _ => Err("expected x,y,width,height with a positive size".to_string()),
+    }
+}
+
+/// A region of a buffer, stored as a list of possibly overlapping rectangles.
+///
+/// Once the list grows beyond a small limit it is collapsed into its bounding box.
+#[derive(Clone, Debug, Default)]
+pub struct Damage {
+    rects: Vec<Rect>,
+}
+
+impl Damage {
+    pub fn full(size: (i32, i32)) -> Self {
+        let mut damage = Self::default();
+        damage.add(Rect::new(0, 0, size.0, size.1));
+        damage
+    }
+
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() || self.rects.iter().any(|r| r.contains(&rect)) {
+            return;
+        }
+        self.rects.retain(|r| !rect.contains(r));
+        self.rects.push(rect);
+        if self.rects.len() > MAX_RECTS {
+            let bounds = self.rects.iter().fold(rect, |a, b| a.union(b));
+            self.rects.clear();
+            self.rects.push(bounds);
+        }
+    }
+
+    pub fn extend(&mut self, other: &Damage) {
+        for rect in &other.rects {
+            self.add(*rect);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+}
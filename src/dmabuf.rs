@@ -0,0 +1,83 @@
+use gbm::Format;
+
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+pub struct DmabufFormat {
+    pub name: &'static str,
+    pub format: Format,
+}
+
+/// The dmabuf formats this client can allocate, in order of preference.
+pub static DMABUF_FORMATS: &[DmabufFormat] = &[
+    DmabufFormat {
+        name: "xrgb8888",
+        format: Format::Xrgb8888,
+    },
+    DmabufFormat {
+        name: "argb8888",
+        format: Format::Argb8888,
+    },
+    DmabufFormat {
+        name: "xbgr8888",
+        format: Format::Xbgr8888,
+    },
+    DmabufFormat {
+        name: "abgr8888",
+        format: Format::Abgr8888,
+    },
+    DmabufFormat {
+        name: "xrgb2101010",
+        format: Format::Xrgb2101010,
+    },
+    DmabufFormat {
+        name: "argb2101010",
+        format: Format::Argb2101010,
+    },
+    DmabufFormat {
+        name: "xbgr2101010",
+        format: Format::Xbgr2101010,
+    },
+    DmabufFormat {
+        name: "abgr2101010",
+        format: Format::Abgr2101010,
+    },
+    DmabufFormat {
+        name: "xbgr16161616f",
+        format: Format::Xbgr16161616f,
+    },
+    DmabufFormat {
+        name: "abgr16161616f",
+        format: Format::Abgr16161616f,
+    },
+    DmabufFormat {
+        name: "rgbx8888",
+        format: Format::Rgbx8888,
+    },
+    DmabufFormat {
+        name: "rgba8888",
+        format: Format::Rgba8888,
+    },
+    DmabufFormat {
+        name: "bgrx8888",
+        format: Format::Bgrx8888,
+    },
+    DmabufFormat {
+        name: "bgra8888",
+        format: Format::Bgra8888,
+    },
+    DmabufFormat {
+        name: "rgb565",
+        format: Format::Rgb565,
+    },
+];
+
+pub fn parse_dmabuf_format(name: &str) -> Result<Format, String> {
+    DMABUF_FORMATS
+        .iter()
+        .find(|f| f.name.eq_ignore_ascii_case(name))
+        .map(|f| f.format)
+        .ok_or_else(|| {
+            let names: Vec<_> = DMABUF_FORMATS.iter().map(|f| f.name).collect();
+            format!("expected one of {}", names.join(", "))
+        })
+}
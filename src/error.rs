@@ -0,0 +1,106 @@
+use {
+    std::fmt::{self, Display, Formatter},
+    wayland_client::{ConnectError, DispatchError},
+};
+
+pub const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0   Success
+  1   The screenshot or recording could not be written
+  2   Invalid command line
+  3   Could not connect to the compositor
+  4   The compositor lacks a required global or capability
+  5   The requested output or toplevel does not exist or is ambiguous
+  6   Protocol error or lost connection
+  7   No mutually supported buffer format
+  8   Buffer allocation failed
+  9   The compositor stopped the capture before the first frame
+  10  Frames kept failing after the capture had started
+  11  The compositor violated the protocol (with --check)";
+
+#[derive(Debug)]
+pub enum Error {
+    Output(String),
+    InvalidOptions(String),
+    Connect(ConnectError),
+    MissingGlobal(&'static str),
+    NoPointer,
+    UnknownOutput(String),
+    AmbiguousOutput(Vec<String>),
+    UnknownToplevel(String),
+    AmbiguousToplevel(Vec<String>),
+    Protocol(DispatchError),
+    NoDmabufDevice,
+    UnsupportedFormat {
+        kind: &'static str,
+        offered: Vec<String>,
+    },
+    Allocation(String),
+    CaptureDenied,
+    CaptureFailed(u32),
+    Violations(u64),
+}
+
+impl Error {
+    pub fn allocation(e: impl Display) -> Self {
+        Error::Allocation(e.to_string())
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Output(_) => 1,
+            Error::InvalidOptions(_) => 2,
+            Error::Connect(_) => 3,
+            Error::MissingGlobal(_) | Error::NoPointer => 4,
+            Error::UnknownOutput(_)
+            | Error::AmbiguousOutput(_)
+            | Error::UnknownToplevel(_)
+            | Error::AmbiguousToplevel(_) => 5,
+            Error::Protocol(_) => 6,
+            Error::NoDmabufDevice | Error::UnsupportedFormat { .. } => 7,
+            Error::Allocation(_) => 8,
+            Error::CaptureDenied => 9,
+            Error::CaptureFailed(_) => 10,
+            Error::Violations(_) => 11,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Output(msg) => f.write_str(msg),
+            Error::InvalidOptions(msg) => write!(f, "Invalid session options: {msg}"),
+            Error::Connect(e) => write!(f, "Could not connect to the compositor: {e}"),
+            Error::MissingGlobal(name) => write!(f, "The compositor does not support {name}"),
+            Error::NoPointer => f.write_str("No seat with a pointer available"),
+            Error::UnknownOutput(name) => write!(f, "Unknown output {name}"),
+            Error::AmbiguousOutput(names) => {
+                write!(f, "Several outputs match: {}", names.join(", "))
+            }
+            Error::UnknownToplevel(id) => write!(f, "Unknown toplevel {id}"),
+            Error::AmbiguousToplevel(ids) => {
+                write!(f, "Several toplevels match: {}", ids.join(", "))
+            }
+            Error::Protocol(e) => write!(f, "Wayland error: {e}"),
+            Error::NoDmabufDevice => {
+                f.write_str("The compositor did not advertise a dmabuf device")
+            }
+            Error::UnsupportedFormat { kind, offered } => write!(
+                f,
+                "No mutually supported {kind} format. The compositor offers: {}",
+                offered.join(", ")
+            ),
+            Error::Allocation(msg) => write!(f, "Could not allocate a buffer: {msg}"),
+            Error::CaptureDenied => {
+                f.write_str("The compositor stopped the capture before a frame was ready")
+            }
+            Error::CaptureFailed(retries) => {
+                write!(f, "Capture failed, giving up after {retries} retries")
+            }
+            Error::Violations(n) => write!(f, "The compositor violated the protocol {n} times"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,99 @@
+use {
+    std::time::Duration, wayland_client::WEnum,
+    wayland_protocols::ext::image_copy_capture::v1::client::ext_image_copy_capture_frame_v1::FailureReason,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+pub enum Action {
+    /// Drop all buffers and wait for the next set of buffer constraints.
+    Renegotiate,
+    /// The session will not produce any more frames.
+    Stop,
+    /// Capture again after the delay.
+    Retry(Duration),
+    /// The retry limit has been reached.
+    GiveUp,
+}
+
+/// Decides how to continue after a frame has failed and counts the failures by reason.
+///
+/// Retries back off exponentially. The retry counter is reset by every ready frame, so
+/// the limit applies to consecutive failures.
+#[derive(Default)]
+pub struct FailurePolicy {
+    max_retries: u32,
+    retries: u32,
+    exhausted: bool,
+    buffer_constraints: u64,
+    stopped: u64,
+    unknown: u64,
+}
+
+impl FailurePolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..Default::default()
+        }
+    }
+
+    pub fn frame_ready(&mut self) {
+        self.retries = 0;
+    }
+
+    pub fn frame_failed(&mut self, reason: WEnum<FailureReason>) -> Action {
+        match reason {
+            WEnum::Value(FailureReason::BufferConstraints) => {
+                self.buffer_constraints += 1;
+                Action::Renegotiate
+            }
+            WEnum::Value(FailureReason::Stopped) => {
+                self.stopped += 1;
+                Action::Stop
+            }
+            _ => {
+                self.unknown += 1;
+                match self.retry() {
+                    Some(delay) => Action::Retry(delay),
+                    None => Action::GiveUp,
+                }
+            }
+        }
+    }
+
+    /// Returns how long to wait before the next attempt or `None` if the retry limit has
+    /// been reached.
+    pub fn retry(&mut self) -> Option<Duration> {
+        if self.retries >= self.max_retries {
+            self.exhausted = true;
+            return None;
+        }
+        let delay = INITIAL_BACKOFF
+            .saturating_mul(1 << self.retries.min(16))
+            .min(MAX_BACKOFF);
+        self.retries += 1;
+        Some(delay)
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    pub fn buffer_constraints(&self) -> u64 {
+        self.buffer_constraints
+    }
+
+    pub fn stopped(&self) -> u64 {
+        self.stopped
+    }
+
+    pub fn unknown(&self) -> u64 {
+        self.unknown
+    }
+}
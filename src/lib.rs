@@ -0,0 +1,35 @@
+//! A client library for the ext-image-copy-capture-v1 protocol.
+//!
+//! [`CaptureClient`] enumerates outputs and toplevels, creates capture sessions for
+//! them, negotiates and allocates buffers and hands out the captured frames.
+
+mod client;
+pub mod damage;
+pub mod dmabuf;
+pub mod error;
+pub mod failure;
+pub mod pixels;
+mod pool;
+pub mod select;
+
+pub use {
+    client::{
+        BufferType, CaptureClient, CaptureEvent, ForeignToplevel, Frame, Output, OutputMode,
+        Pacing, SessionId, SessionOptions, Source, Violation,
+    },
+    error::Error,
+    pool::BufferFormat,
+};
+
+use std::time::Duration;
+
+pub fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    Duration::new(ts.tv_sec as _, ts.tv_nsec as _)
+}
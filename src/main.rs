@@ -1,257 +1,1264 @@
+mod bench;
+mod record;
+mod screenshot;
+mod stats;
+
 use {
-    clap::{Args, Parser},
-    drm::node::NodeType,
-    gbm::{BufferObjectFlags, Format::Xrgb8888},
-    memfile::Seal,
-    std::{collections::HashMap, fs::File, os::fd::AsFd, process},
-    wayland_backend::client::ObjectId,
+    clap::{error::ErrorKind, Args, CommandFactory, Parser, ValueEnum},
+    record::Recorder,
+    serde_json::json,
+    stats::Stats,
+    std::{
+        mem,
+        path::PathBuf,
+        process,
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+        time::Duration,
+    },
     wayland_client::{
-        delegate_noop, event_created_child,
+        delegate_noop,
         protocol::{
-            wl_buffer,
+            wl_buffer::WlBuffer,
             wl_callback::{self, WlCallback},
-            wl_compositor,
-            wl_display::WlDisplay,
-            wl_output::{self, WlOutput},
+            wl_compositor::WlCompositor,
+            wl_output::Transform,
             wl_registry,
-            wl_shm::{Format, WlShm},
-            wl_shm_pool::WlShmPool,
-            wl_subcompositor,
+            wl_shm::Format,
+            wl_subcompositor::WlSubcompositor,
             wl_subsurface::WlSubsurface,
-            wl_surface,
+            wl_surface::WlSurface,
         },
-        Connection, Dispatch, Proxy, QueueHandle,
+        Connection, Dispatch, EventQueue, QueueHandle,
+    },
+    wayland_copy_capture_test_client::{
+        damage::{self, Rect},
+        dmabuf,
+        error::EXIT_STATUS_HELP,
+        monotonic_now, pixels,
+        select::{self, Pattern, Pick, ToplevelSelector},
+        BufferType, CaptureClient, CaptureEvent, Error, ForeignToplevel, Frame, Output, Pacing,
+        SessionId, SessionOptions, Source,
     },
     wayland_protocols::{
-        ext::{
-            foreign_toplevel_list::v1::client::{
-                ext_foreign_toplevel_handle_v1,
-                ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1,
-                ext_foreign_toplevel_list_v1::{
-                    self, ExtForeignToplevelListV1, EVT_TOPLEVEL_OPCODE,
-                },
-            },
-            image_capture_source::v1::client::{
-                ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
-                ext_image_capture_source_v1::ExtImageCaptureSourceV1,
-                ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
-            },
-            image_copy_capture::v1::client::{
-                ext_image_copy_capture_frame_v1,
-                ext_image_copy_capture_frame_v1::ExtImageCopyCaptureFrameV1,
-                ext_image_copy_capture_manager_v1::{ExtImageCopyCaptureManagerV1, Options},
-                ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
-            },
-        },
         wp::{
-            linux_dmabuf::zv1::client::{
-                zwp_linux_buffer_params_v1::{Flags, ZwpLinuxBufferParamsV1},
-                zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
-            },
             single_pixel_buffer::v1::client::wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1,
-            viewporter::client::{wp_viewport::WpViewport, wp_viewporter},
+            viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
         },
         xdg::{
             decoration::zv1::client::{
                 zxdg_decoration_manager_v1::ZxdgDecorationManagerV1,
                 zxdg_toplevel_decoration_v1::{self, ZxdgToplevelDecorationV1},
             },
-            shell::client::{xdg_surface, xdg_toplevel, xdg_wm_base},
+            shell::client::{
+                xdg_surface::{self, XdgSurface},
+                xdg_toplevel::{self, XdgToplevel},
+                xdg_wm_base::{self, XdgWmBase},
+            },
         },
     },
-    wl_buffer::WlBuffer,
-    wl_compositor::WlCompositor,
-    wl_subcompositor::WlSubcompositor,
-    wl_surface::WlSurface,
-    wp_viewporter::WpViewporter,
-    xdg_surface::XdgSurface,
-    xdg_toplevel::XdgToplevel,
-    xdg_wm_base::XdgWmBase,
 };
 
 #[derive(Parser, Debug)]
+#[command(after_help = EXIT_STATUS_HELP)]
 struct Cli {
+    /// List the outputs and toplevels and exit. This is the default without a target.
+    #[clap(long, conflicts_with_all = ["output", "toplevel", "app_id", "title"])]
+    list: bool,
+    /// Print outputs and toplevels as they are added, changed and removed until
+    /// interrupted.
+    #[clap(long, conflicts_with_all = ["list", "output", "toplevel", "app_id", "title"])]
+    watch: bool,
+    /// Print the list as a single JSON document.
+    #[clap(long, requires = "list")]
+    json: bool,
     #[clap(long)]
     stretch: bool,
+    /// Only show and save this part of the captured image, given as x,y,width,height in
+    /// buffer pixels.
+    #[clap(long, value_parser = damage::parse_rect)]
+    region: Option<Rect>,
     #[clap(flatten)]
     target: CliTarget,
     #[clap(long)]
     dmabuf: bool,
+    /// Use this dmabuf format instead of picking one from the supported formats.
+    #[clap(long, requires = "dmabuf", value_parser = dmabuf::parse_dmabuf_format)]
+    dmabuf_format: Option<gbm::Format>,
+    /// Write the first captured frame to a PNG file and exit without opening a window.
+    #[clap(long, conflicts_with = "dmabuf")]
+    screenshot: Option<PathBuf>,
+    /// Append captured frames to a YUV4MPEG2 file.
+    #[clap(long, conflicts_with_all = ["dmabuf", "screenshot"])]
+    record: Option<PathBuf>,
+    /// Stop recording after this many captured frames.
+    #[clap(long, requires = "record")]
+    frames: Option<u64>,
+    /// Stop recording after this many seconds.
+    #[clap(long, requires = "record")]
+    duration: Option<f64>,
+    /// Frame rate of the recorded stream.
+    #[clap(long, requires = "record", default_value_t = 60)]
+    record_fps: u32,
+    /// Print frame timing statistics on exit.
+    #[clap(long)]
+    stats: bool,
+    /// Also print frame timing statistics every this many seconds.
+    #[clap(long)]
+    stats_interval: Option<f64>,
+    /// How the pointer cursor is captured.
+    #[clap(long, value_enum, default_value_t = CursorMode::Painted)]
+    cursor: CursorMode,
+    /// Use this shm format instead of picking one from the supported formats.
+    #[clap(long, value_parser = pixels::parse_shm_format)]
+    shm_format: Option<Format>,
+    /// Maximum number of buffers per capture session.
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(2..))]
+    buffers: u32,
+    /// Re-create the capture session when the compositor stops it.
+    #[clap(long)]
+    restart: bool,
+    /// Give up after this many consecutive failed frames or restarts.
+    #[clap(long, default_value_t = 5)]
+    max_retries: u32,
+    /// Keep the window open when the target goes away and capture it again when it
+    /// reappears. Outputs are matched by name, toplevels by --app-id and --title or,
+    /// with --toplevel, by app_id.
+    #[clap(long, conflicts_with_all = ["screenshot", "record"])]
+    follow: bool,
+    /// When to capture the next frame.
+    #[clap(long, value_enum, default_value_t = CliPacing::Unlimited)]
+    pacing: CliPacing,
+    /// Capture every combination of buffer type, format and buffer count without a
+    /// window and print a JSON report.
+    #[clap(long, conflicts_with_all = ["screenshot", "record", "follow", "dmabuf"])]
+    bench: bool,
+    /// Frames captured with every combination.
+    #[clap(long, requires = "bench", default_value_t = 300)]
+    bench_frames: u64,
+    /// Comma-separated buffer counts to try.
+    #[clap(
+        long,
+        requires = "bench",
+        value_delimiter = ',',
+        default_value = "2,3,4",
+        value_parser = clap::value_parser!(u32).range(2..),
+    )]
+    bench_buffers: Vec<u32>,
+    /// Give up on a combination after this many seconds.
+    #[clap(long, requires = "bench", default_value_t = 10.0, value_parser = parse_positive)]
+    bench_timeout: f64,
+    /// Report events that break the ext-image-copy-capture-v1 specification and exit
+    /// with status 11 if there were any.
+    #[clap(long, conflicts_with = "bench")]
+    check: bool,
+    /// Frame rate limit of --pacing max-fps.
+    #[clap(long, required_if_eq("pacing", "max-fps"), value_parser = parse_positive)]
+    max_fps: Option<f64>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum CliPacing {
+    /// Capture the next frame as soon as a buffer is available.
+    Unlimited,
+    /// Capture at most --max-fps frames per second.
+    MaxFps,
+    /// Capture a frame whenever the compositor asks for a new frame of the window.
+    FrameCallback,
+    /// Capture a frame whenever SIGUSR1 is received.
+    OnDemand,
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fps) if fps > 0.0 && fps.is_finite() => Ok(fps),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+static FRAME_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_frame(_: libc::c_int) {
+    FRAME_REQUESTED.store(true, Relaxed);
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum CursorMode {
+    /// Paint the cursor into the captured frames.
+    Painted,
+    /// Capture frames without the cursor.
+    Hidden,
+    /// Capture frames without the cursor and the cursor in its own session.
+    Separate,
 }
 
 #[derive(Args, Debug)]
-#[group(multiple = false)]
 struct CliTarget {
+    /// Capture the output whose connector name, description, make or model matches this
+    /// glob or /regex/, e.g. `DP-*`. Can be repeated to capture several sources.
+    #[clap(long, value_parser = select::parse_pattern)]
+    output: Vec<Pattern>,
+    /// Capture the toplevel with this identifier. Can be repeated to capture several
+    /// sources.
     #[clap(long)]
-    output: Option<String>,
-    #[clap(long)]
-    toplevel: Option<String>,
-}
-
-enum Target {
-    None,
-    Output(String),
-    Toplevel(String),
+    toplevel: Vec<String>,
+    /// Capture the toplevel whose app_id matches this glob or /regex/.
+    #[clap(long, value_parser = select::parse_pattern)]
+    app_id: Option<Pattern>,
+    /// Capture the toplevel whose title matches this glob or /regex/.
+    #[clap(long, value_parser = select::parse_pattern)]
+    title: Option<Pattern>,
+    /// Which toplevel to capture if several match --app-id and --title.
+    #[clap(long, value_enum, default_value_t = Pick::Error)]
+    pick: Pick,
 }
 
 fn main() {
     let cli = Cli::parse();
-    let target = match cli.target.output {
-        Some(s) => Target::Output(s),
-        None => match cli.target.toplevel {
-            Some(s) => Target::Toplevel(s),
-            None => Target::None,
-        },
+    if let Err(e) = run(cli) {
+        eprintln!("{e}");
+        process::exit(e.exit_code());
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    let selector = ToplevelSelector {
+        app_id: cli.target.app_id,
+        title: cli.target.title,
     };
+    let has_selector = selector.app_id.is_some() || selector.title.is_some();
+    let num_sources = cli.target.output.len() + cli.target.toplevel.len() + has_selector as usize;
+    if num_sources > 1 && (cli.screenshot.is_some() || cli.record.is_some() || cli.bench) {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--screenshot, --record and --bench capture a single source",
+            )
+            .exit();
+    }
+    if cli.bench && num_sources == 0 {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--bench requires a source to capture",
+            )
+            .exit();
+    }
+    if cli.max_fps.is_some() && cli.pacing != CliPacing::MaxFps {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--max-fps requires --pacing max-fps",
+            )
+            .exit();
+    }
 
-    let conn = Connection::connect_to_env().unwrap();
+    let recorder = match cli.record {
+        Some(path) => {
+            let duration = cli.duration.map(Duration::from_secs_f64);
+            let recorder = Recorder::create(&path, cli.record_fps, cli.frames, duration)
+                .map_err(|e| Error::Output(format!("Could not create {}: {e}", path.display())))?;
+            Some(recorder)
+        }
+        None => None,
+    };
 
-    let mut event_queue = conn.new_event_queue();
-    let qhandle = event_queue.handle();
+    let stats_interval = cli.stats_interval.map(Duration::from_secs_f64);
+    let stats = cli.stats || stats_interval.is_some();
+
+    let mut client = CaptureClient::connect()?;
 
-    let display = conn.display();
-    display.get_registry(&qhandle, ());
+    if cli.watch {
+        return watch(&mut client);
+    }
+    if num_sources == 0 || cli.list {
+        if cli.json {
+            println!("{}", list_json(&client));
+        } else {
+            print_outputs(&client);
+            print_toplevels(&client);
+        }
+        return Ok(());
+    }
+
+    let mut targets = vec![];
+    for pattern in &cli.target.output {
+        let output = client
+            .find_output(pattern)
+            .inspect_err(|_| print_outputs(&client))?;
+        let name = output.name.clone();
+        targets.push((Source::Output(name.clone()), FollowTarget::Output(name)));
+    }
+    for id in &cli.target.toplevel {
+        let Some(toplevel) = client.toplevels().find(|t| &t.id == id) else {
+            print_toplevels(&client);
+            return Err(Error::UnknownToplevel(id.clone()));
+        };
+        let selector = match &toplevel.app_id[..] {
+            "" => ToplevelSelector {
+                title: Some(select::literal(&toplevel.title)),
+                ..Default::default()
+            },
+            app_id => ToplevelSelector {
+                app_id: Some(select::literal(app_id)),
+                ..Default::default()
+            },
+        };
+        targets.push((
+            Source::Toplevel(id.clone()),
+            FollowTarget::Toplevel(selector),
+        ));
+    }
+    if has_selector {
+        let toplevel = client
+            .find_toplevel(&selector, cli.target.pick)
+            .inspect_err(|_| print_toplevels(&client))?;
+        let source = Source::Toplevel(toplevel.id.clone());
+        targets.push((source, FollowTarget::Toplevel(selector)));
+    }
+
+    let options = SessionOptions {
+        paint_cursors: cli.cursor == CursorMode::Painted,
+        buffer_type: match cli.dmabuf {
+            true => BufferType::Dmabuf,
+            false => BufferType::Shm,
+        },
+        shm_format: cli.shm_format,
+        dmabuf_format: cli.dmabuf_format,
+        buffers: cli.buffers as usize,
+        displayable: cli.screenshot.is_none(),
+        restart: cli.restart,
+        max_retries: cli.max_retries,
+        pacing: match (cli.pacing, cli.max_fps) {
+            (CliPacing::MaxFps, Some(fps)) => Pacing::MaxFps(fps),
+            (CliPacing::FrameCallback | CliPacing::OnDemand, _) => Pacing::OnDemand,
+            _ => Pacing::Unlimited,
+        },
+        // A followed target can go away again before its first frame.
+        allow_early_stop: cli.follow,
+        check: cli.check,
+    };
+    // The cursor image only changes when the cursor does, so it is never paced.
+    let cursor_options = (cli.cursor == CursorMode::Separate).then(|| SessionOptions {
+        buffer_type: BufferType::Shm,
+        pacing: Pacing::Unlimited,
+        ..options.clone()
+    });
+    if cli.bench {
+        let bench = bench::Bench {
+            frames: cli.bench_frames,
+            buffers: cli.bench_buffers,
+            timeout: Duration::from_secs_f64(cli.bench_timeout),
+        };
+        let report = bench::run(&mut client, &targets[0].0, &options, &bench)?;
+        println!("{report}");
+        return Ok(());
+    }
+    if cli.pacing == CliPacing::OnDemand {
+        unsafe {
+            libc::signal(
+                libc::SIGUSR1,
+                request_frame as extern "C" fn(_) as libc::sighandler_t,
+            );
+        }
+    }
+    let tiles = targets
+        .into_iter()
+        .map(|(source, follow)| Tile {
+            source,
+            follow: cli.follow.then_some(follow),
+            video: None,
+            video_frame: None,
+            frame_wanted: false,
+            cursor: None,
+            stale_toplevel: None,
+            stats: stats.then(|| Stats::new(stats_interval)),
+        })
+        .collect();
 
-    display.sync(&qhandle, InitialRoundtrip);
+    let conn = client.connection().clone();
+    let mut event_queue = conn.new_event_queue();
+    let qhandle = event_queue.handle();
+    conn.display().get_registry(&qhandle, ());
 
     let mut state = State {
-        display,
-        target,
-        fullscreen: cli.stretch,
         running: true,
+        pacing: cli.pacing,
+        qhandle: qhandle.clone(),
+        fullscreen: cli.stretch,
+        region: cli.region,
         wm_base: None,
         wl_compositor: None,
-        wl_shm: None,
         wp_viewporter: None,
         wl_subcompositor: None,
         wp_single_pixel_buffer_manager: None,
         zxdg_decoration_manager_v1: None,
-        ext_output_image_capture_source_manager_v1: None,
-        ext_foreign_toplevel_image_capture_source_manager_v1: None,
-        ext_foreign_toplevel_list_v1: None,
-        ext_image_copy_capture_manager_v1: None,
-        zwp_linux_dmabuf_v1: None,
-        objects: None,
-        outputs: Default::default(),
-        capture_size: (0, 0),
-        foreign_toplevels: Default::default(),
-        next_buffer_id: 0,
-        dmabuf: cli.dmabuf,
-        dmabuf_device: 0,
-        gbm: None,
-        dmabuf_modifiers: vec![],
+        window: None,
+        tiles,
+        options,
+        cursor_options,
         size: (1, 1),
-        buffers: vec![],
+        screenshot: cli.screenshot,
+        recorder,
+        error: None,
+        check: cli.check,
+        violations: 0,
     };
 
-    while state.running {
-        event_queue.blocking_dispatch(&mut state).unwrap();
+    for idx in 0..state.tiles.len() {
+        state
+            .start_capture(&mut client, idx)
+            .inspect_err(|e| match e {
+                Error::UnknownOutput(_) => print_outputs(&client),
+                Error::UnknownToplevel(_) => print_toplevels(&client),
+                _ => {}
+            })?;
+    }
+
+    event_queue.roundtrip(&mut state).map_err(Error::Protocol)?;
+    if state.screenshot.is_none() {
+        state.window = Some(state.create_window(&qhandle)?);
+    }
+
+    let result = state.run(&mut client, &mut event_queue);
+
+    for tile in &state.tiles {
+        if let Some(stats) = &tile.stats {
+            if state.tiles.len() > 1 {
+                eprintln!("{}:", source_name(&tile.source));
+            }
+            stats.print_summary();
+        }
+        if let Some(f) = tile.video.and_then(|video| client.failures(video)) {
+            if f.buffer_constraints() + f.stopped() + f.unknown() > 0 {
+                eprintln!(
+                    "Failed frames: {} buffer_constraints, {} stopped, {} unknown",
+                    f.buffer_constraints(),
+                    f.stopped(),
+                    f.unknown()
+                );
+            }
+        }
+    }
+    result?;
+    if let Some(recorder) = state.recorder.take() {
+        recorder
+            .finish()
+            .map_err(|e| Error::Output(format!("Could not finish recording: {e}")))?;
+    }
+    if state.violations > 0 {
+        return Err(Error::Violations(state.violations));
+    }
+    Ok(())
+}
+
+fn source_name(source: &Source) -> String {
+    match source {
+        Source::Output(name) => format!("output {name}"),
+        Source::Toplevel(id) => format!("toplevel {id}"),
+    }
+}
+
+fn print_toplevels(client: &CaptureClient) {
+    let mut handles: Vec<_> = client.toplevels().collect();
+    handles.sort_by_cached_key(|t| &t.app_id);
+
+    eprintln!("Available toplevels:");
+    for handle in handles {
+        println!("  {}", toplevel_line(handle));
+    }
+}
+
+fn print_outputs(client: &CaptureClient) {
+    let mut outputs: Vec<_> = client.outputs().collect();
+    outputs.sort_by_cached_key(|t| &t.name);
+
+    eprintln!("Available outputs:");
+    for output in outputs {
+        println!("  {} - {}", output.name, output.description);
+        println!("    make: {}, model: {}", output.make, output.model);
+        if let Some(mode) = output.mode {
+            println!(
+                "    mode: {}x{} @ {:.3} Hz, scale: {}, transform: {:?}",
+                mode.width,
+                mode.height,
+                mode.refresh as f64 / 1000.0,
+                output.scale,
+                output.transform,
+            );
+        }
+        println!(
+            "    position: {},{}, physical size: {}x{} mm",
+            output.position.0, output.position.1, output.physical_size.0, output.physical_size.1,
+        );
+        if let (Some(pos), Some(size)) = (output.logical_position, output.logical_size) {
+            println!("    logical: {}x{} at {},{}", size.0, size.1, pos.0, pos.1);
+        }
+    }
+}
+
+/// Prints the output and toplevel events until the connection is lost.
+///
+/// The objects announced while connecting are printed as added.
+fn watch(client: &mut CaptureClient) -> Result<(), Error> {
+    loop {
+        let event = client.wait_event()?;
+        let time = monotonic_now();
+        let time = format!("{}.{:06}", time.as_secs(), time.subsec_micros());
+        match event {
+            CaptureEvent::OutputAdded(o) => println!("{time} + output {}", output_line(&o)),
+            CaptureEvent::OutputChanged(o) => println!("{time} ~ output {}", output_line(&o)),
+            CaptureEvent::OutputRemoved(o) => println!("{time} - output {}", output_line(&o)),
+            CaptureEvent::ToplevelAdded(t) => println!("{time} + toplevel {}", toplevel_line(&t)),
+            CaptureEvent::ToplevelChanged(t) => {
+                println!("{time} ~ toplevel {}", toplevel_line(&t))
+            }
+            CaptureEvent::ToplevelClosed(t) => {
+                println!("{time} - toplevel {}", toplevel_line(&t))
+            }
+            _ => {}
+        }
+    }
+}
+
+fn output_line(output: &Output) -> String {
+    let mode = match output.mode {
+        Some(m) => format!(
+            "{}x{}@{:.3}Hz",
+            m.width,
+            m.height,
+            m.refresh as f64 / 1000.0
+        ),
+        None => "no mode".to_string(),
+    };
+    format!(
+        "{} - {} - {mode} scale {} {}",
+        output.name,
+        output.description,
+        output.scale,
+        transform_name(output.transform)
+    )
+}
+
+fn toplevel_line(toplevel: &ForeignToplevel) -> String {
+    format!("{} - {} - {}", toplevel.id, toplevel.app_id, toplevel.title)
+}
+
+fn list_json(client: &CaptureClient) -> serde_json::Value {
+    let mut outputs: Vec<_> = client.outputs().collect();
+    outputs.sort_by_cached_key(|t| &t.name);
+    let outputs: Vec<_> = outputs
+        .into_iter()
+        .map(|o| {
+            json!({
+                "name": o.name,
+                "description": o.description,
+                "make": o.make,
+                "model": o.model,
+                "mode": o.mode.map(|m| json!({
+                    "width": m.width,
+                    "height": m.height,
+                    "refresh_mhz": m.refresh,
+                })),
+                "scale": o.scale,
+                "transform": transform_name(o.transform),
+            })
+        })
+        .collect();
+    let mut toplevels: Vec<_> = client.toplevels().collect();
+    toplevels.sort_by_cached_key(|t| &t.app_id);
+    let toplevels: Vec<_> = toplevels
+        .into_iter()
+        .map(|t| {
+            json!({
+                "identifier": t.id,
+                "app_id": t.app_id,
+                "title": t.title,
+            })
+        })
+        .collect();
+    json!({
+        "outputs": outputs,
+        "toplevels": toplevels,
+        "source_managers": client.source_managers(),
+    })
+}
+
+fn transform_name(transform: Transform) -> &'static str {
+    match transform {
+        Transform::Normal => "normal",
+        Transform::_90 => "90",
+        Transform::_180 => "180",
+        Transform::_270 => "270",
+        Transform::Flipped => "flipped",
+        Transform::Flipped90 => "flipped-90",
+        Transform::Flipped180 => "flipped-180",
+        Transform::Flipped270 => "flipped-270",
+        _ => "unknown",
     }
 }
 
 struct State {
-    display: WlDisplay,
-    target: Target,
     running: bool,
+    pacing: CliPacing,
+    qhandle: QueueHandle<Self>,
     fullscreen: bool,
+    region: Option<Rect>,
     wm_base: Option<XdgWmBase>,
     wl_compositor: Option<WlCompositor>,
-    wl_shm: Option<WlShm>,
     wp_viewporter: Option<WpViewporter>,
     wl_subcompositor: Option<WlSubcompositor>,
     wp_single_pixel_buffer_manager: Option<WpSinglePixelBufferManagerV1>,
     zxdg_decoration_manager_v1: Option<ZxdgDecorationManagerV1>,
-    ext_output_image_capture_source_manager_v1: Option<ExtOutputImageCaptureSourceManagerV1>,
-    ext_foreign_toplevel_image_capture_source_manager_v1:
-        Option<ExtForeignToplevelImageCaptureSourceManagerV1>,
-    ext_foreign_toplevel_list_v1: Option<ExtForeignToplevelListV1>,
-    ext_image_copy_capture_manager_v1: Option<ExtImageCopyCaptureManagerV1>,
-    zwp_linux_dmabuf_v1: Option<ZwpLinuxDmabufV1>,
-    objects: Option<Objects>,
-    outputs: HashMap<ObjectId, Output>,
-    capture_size: (i32, i32),
-    foreign_toplevels: HashMap<ObjectId, ForeignToplevel>,
-    next_buffer_id: u64,
-    dmabuf: bool,
-    dmabuf_device: libc::dev_t,
-    gbm: Option<gbm::Device<File>>,
-    dmabuf_modifiers: Vec<u64>,
+    window: Option<Window>,
+    tiles: Vec<Tile>,
+    options: SessionOptions,
+    cursor_options: Option<SessionOptions>,
     size: (i32, i32),
-    buffers: Vec<Buffer>,
+    screenshot: Option<PathBuf>,
+    recorder: Option<Recorder>,
+    error: Option<Error>,
+    check: bool,
+    violations: u64,
 }
 
-struct Output {
-    output: WlOutput,
-    name: String,
+/// One captured source and its place in the window grid.
+struct Tile {
+    source: Source,
+    follow: Option<FollowTarget>,
+    /// The video session or `None` while the source is gone.
+    video: Option<SessionId>,
+    video_frame: Option<Frame>,
+    /// A frame is to be requested from the video session with on-demand pacing.
+    frame_wanted: bool,
+    cursor: Option<Cursor>,
+    /// The identifier of the followed toplevel that went away.
+    stale_toplevel: Option<String>,
+    stats: Option<Stats>,
 }
 
-struct ForeignToplevel {
-    handle: ExtForeignToplevelHandleV1,
-    id: String,
-    title: String,
-    app_id: String,
+enum FollowTarget {
+    Output(String),
+    Toplevel(ToplevelSelector),
 }
 
-struct Buffer {
-    id: u64,
-    buffer: WlBuffer,
-    free: bool,
-    ready: bool,
-    size: (i32, i32),
-    _bo_opt: Option<gbm::BufferObject<()>>,
+struct Cursor {
+    session: SessionId,
+    frame: Option<Frame>,
+    visible: bool,
+    position: (i32, i32),
+    hotspot: (i32, i32),
+    pending_hotspot: (i32, i32),
 }
 
-struct Objects {
+struct Window {
     root_surface: WlSurface,
     root_buffer: WlBuffer,
     root_viewport: WpViewport,
     _xdg_surface: XdgSurface,
     _xdg_toplevel: XdgToplevel,
+    /// Nothing is committed before the first configure has been acknowledged.
+    configured: bool,
+    tiles: Vec<TileSurfaces>,
+}
+
+struct TileSurfaces {
     video_surface: WlSurface,
     video_subsurface: WlSubsurface,
     video_viewport: WpViewport,
     video_buffer_size: (i32, i32),
-    session: ExtImageCopyCaptureSessionV1,
-    frame: Option<ExtImageCopyCaptureFrameV1>,
+    video_buffer_transform: Transform,
+    /// The part of the buffer that is shown, in buffer coordinates.
+    video_source: Option<Rect>,
+    /// Where the video is shown in the window.
+    video_area: Rect,
+    /// The region lies outside the video buffer.
+    region_outside: bool,
+    cursor_surface: WlSurface,
+    cursor_subsurface: WlSubsurface,
+    cursor_viewport: WpViewport,
+    cursor_buffer_size: (i32, i32),
+    cursor_attached: bool,
+}
+
+impl TileSurfaces {
+    /// The size of the video in surface coordinates.
+    fn size(&self) -> (i32, i32) {
+        let size = match self.video_source {
+            Some(rect) => (rect.width, rect.height),
+            None => self.video_buffer_size,
+        };
+        match transform_swaps_axes(self.video_buffer_transform) {
+            true => (size.1, size.0),
+            false => size,
+        }
+    }
+
+    /// Places the cursor over the video and commits the cursor surface. The position
+    /// takes effect with the next commit of the root surface.
+    fn place_cursor(&mut self, cursor: Option<&mut Cursor>) {
+        let buffer_size = self.size();
+        let Some(cursor) = cursor else {
+            if self.cursor_attached {
+                self.cursor_surface.attach(None, 0, 0);
+                self.cursor_surface.commit();
+                self.cursor_attached = false;
+            }
+            return;
+        };
+        // The cursor position is given in the transformed coordinates of the
+        // video buffer, so it scales along with the video.
+        let source =
+            self.video_source
+                .map_or(Rect::new(0, 0, buffer_size.0, buffer_size.1), |rect| {
+                    buffer_rect_to_surface(
+                        rect,
+                        self.video_buffer_transform,
+                        self.video_buffer_size,
+                    )
+                });
+        let position = (cursor.position.0 - source.x, cursor.position.1 - source.y);
+        let inside =
+            (0..source.width).contains(&position.0) && (0..source.height).contains(&position.1);
+        if !cursor.visible || !inside {
+            if self.cursor_attached {
+                self.cursor_surface.attach(None, 0, 0);
+                self.cursor_attached = false;
+            }
+        } else if let Some(frame) = cursor.frame.take() {
+            self.cursor_surface.attach(Some(&frame.buffer), 0, 0);
+            self.cursor_surface
+                .damage_buffer(0, 0, frame.size.0, frame.size.1);
+            self.cursor_buffer_size = frame.size;
+            self.cursor_attached = true;
+        }
+        let scale = (
+            self.video_area.width as f64 / buffer_size.0 as f64,
+            self.video_area.height as f64 / buffer_size.1 as f64,
+        );
+        let scaled = |v: i32, s: f64| (v as f64 * s).round() as i32;
+        self.cursor_subsurface.set_position(
+            self.video_area.x + scaled(position.0 - cursor.hotspot.0, scale.0),
+            self.video_area.y + scaled(position.1 - cursor.hotspot.1, scale.1),
+        );
+        self.cursor_viewport.set_destination(
+            scaled(self.cursor_buffer_size.0, scale.0).max(1),
+            scaled(self.cursor_buffer_size.1, scale.1).max(1),
+        );
+        self.cursor_surface.commit();
+    }
+}
+
+fn global<'a, T>(global: &'a Option<T>, name: &'static str) -> Result<&'a T, Error> {
+    global.as_ref().ok_or(Error::MissingGlobal(name))
+}
+
+fn transform_swaps_axes(transform: Transform) -> bool {
+    matches!(
+        transform,
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+    )
+}
+
+/// Returns the part of a buffer of the given size that lies inside the region or an
+/// error if the region lies outside the buffer.
+fn crop_rect(region: Option<Rect>, size: (i32, i32)) -> Result<Option<Rect>, String> {
+    let Some(region) = region else {
+        return Ok(None);
+    };
+    let rect = region.intersect(&Rect::new(0, 0, size.0, size.1));
+    if rect.is_empty() {
+        return Err(format!(
+            "the region {},{},{},{} lies outside the {}x{} frame",
+            region.x, region.y, region.width, region.height, size.0, size.1
+        ));
+    }
+    Ok(Some(rect))
+}
+
+/// Converts a rectangle in buffer coordinates to surface coordinates.
+fn buffer_rect_to_surface(rect: Rect, transform: Transform, size: (i32, i32)) -> Rect {
+    let (rotations, flipped) = match transform {
+        Transform::_90 => (1, false),
+        Transform::_180 => (2, false),
+        Transform::_270 => (3, false),
+        Transform::Flipped => (0, true),
+        Transform::Flipped90 => (1, true),
+        Transform::Flipped180 => (2, true),
+        Transform::Flipped270 => (3, true),
+        _ => (0, false),
+    };
+    let (mut x1, mut y1) = (rect.x, rect.y);
+    let (mut x2, mut y2) = (rect.x + rect.width, rect.y + rect.height);
+    let (mut width, mut height) = size;
+    // The buffer contents are the surface contents flipped and then rotated
+    // counter-clockwise, so undo the rotation first.
+    for _ in 0..rotations {
+        (x1, y1, x2, y2) = (height - y2, x1, height - y1, x2);
+        (width, height) = (height, width);
+    }
+    if flipped {
+        (x1, x2) = (width - x2, width - x1);
+    }
+    Rect::new(x1, y1, x2 - x1, y2 - y1)
+}
+
+/// Returns the number of columns and rows of a grid with `n` cells.
+fn grid_size(n: usize) -> (i32, i32) {
+    let cols = (n as f64).sqrt().ceil().max(1.0) as i32;
+    let rows = (n as i32 + cols - 1) / cols;
+    (cols, rows.max(1))
 }
 
 impl State {
-    fn render_frame(&mut self) {
-        let obj = self.objects.as_mut().unwrap();
-        if let Some(buffer) = self.buffers.iter_mut().find(|b| b.ready && b.free) {
-            buffer.ready = false;
-            buffer.free = false;
-            obj.video_surface.attach(Some(&buffer.buffer), 0, 0);
-            obj.video_surface
-                .damage_buffer(0, 0, buffer.size.0, buffer.size.1);
-            obj.video_buffer_size = buffer.size;
+    fn run(
+        &mut self,
+        client: &mut CaptureClient,
+        event_queue: &mut EventQueue<Self>,
+    ) -> Result<(), Error> {
+        while self.running {
+            client.dispatch_pending()?;
+            while let Some(event) = client.next_event() {
+                self.handle_event(client, event);
+            }
+            event_queue
+                .dispatch_pending(self)
+                .map_err(Error::Protocol)?;
+            let requested = FRAME_REQUESTED.swap(false, Relaxed);
+            for tile in &mut self.tiles {
+                if mem::take(&mut tile.frame_wanted) || requested {
+                    if let Some(video) = tile.video {
+                        client.request_frame(video);
+                    }
+                }
+            }
+            if let Some(e) = self.error.take() {
+                return Err(e);
+            }
+            if self.running {
+                client.read_events(None)?;
+            }
         }
-        if self.fullscreen {
-            let mut video_size = obj.video_buffer_size;
-            if video_size.0 != self.size.0 {
-                video_size.1 = video_size.1 * self.size.0 / video_size.0;
-                video_size.0 = self.size.0;
+        Ok(())
+    }
+
+    /// Stops the event loop and reports the error from `main`.
+    fn fail(&mut self, error: Error) {
+        self.error.get_or_insert(error);
+        self.running = false;
+    }
+
+    fn video_tile(&self, session: SessionId) -> Option<usize> {
+        self.tiles.iter().position(|t| t.video == Some(session))
+    }
+
+    fn cursor_mut(&mut self, session: SessionId) -> Option<&mut Cursor> {
+        self.tiles
+            .iter_mut()
+            .find_map(|t| t.cursor.as_mut().filter(|c| c.session == session))
+    }
+
+    fn handle_event(&mut self, client: &mut CaptureClient, event: CaptureEvent) {
+        match event {
+            CaptureEvent::Frame(frame) => {
+                if let Some(idx) = self.video_tile(frame.session) {
+                    self.video_frame_ready(client, idx, frame);
+                    return;
+                }
+                let Some(cursor) = self.cursor_mut(frame.session) else {
+                    client.release(&frame);
+                    return;
+                };
+                cursor.hotspot = cursor.pending_hotspot;
+                let session = cursor.session;
+                if let Some(old) = cursor.frame.replace(frame) {
+                    client.release(&old);
+                }
+                self.move_cursor(session);
+            }
+            CaptureEvent::FrameFailed { session, reason } => {
+                eprintln!("Frame failed: {:?}", reason);
+                if let Some(idx) = self.video_tile(session) {
+                    if let Some(stats) = &mut self.tiles[idx].stats {
+                        stats.frame_failed();
+                    }
+                }
+            }
+            CaptureEvent::CursorEnter(session) => {
+                if let Some(cursor) = self.cursor_mut(session) {
+                    cursor.visible = true;
+                }
+            }
+            CaptureEvent::CursorLeave(session) => {
+                if let Some(cursor) = self.cursor_mut(session) {
+                    cursor.visible = false;
+                }
+                self.move_cursor(session);
+            }
+            CaptureEvent::CursorPosition { session, x, y } => {
+                if let Some(cursor) = self.cursor_mut(session) {
+                    cursor.position = (x, y);
+                }
+                self.move_cursor(session);
+            }
+            CaptureEvent::CursorHotspot { session, x, y } => {
+                if let Some(cursor) = self.cursor_mut(session) {
+                    cursor.pending_hotspot = (x, y);
+                }
+            }
+            CaptureEvent::Stopped(session) => {
+                let Some(idx) = self.video_tile(session) else {
+                    return;
+                };
+                self.stop_capture(client, idx);
+                if self
+                    .tiles
+                    .iter()
+                    .all(|t| t.video.is_none() && t.follow.is_none())
+                {
+                    self.running = false;
+                }
+            }
+            CaptureEvent::OutputAdded(o) | CaptureEvent::OutputChanged(o) => {
+                let idx = self.tiles.iter().position(|t| {
+                    t.video.is_none()
+                        && matches!(&t.follow, Some(FollowTarget::Output(name)) if *name == o.name)
+                });
+                if let Some(idx) = idx {
+                    self.resume_capture(client, idx, Source::Output(o.name));
+                }
+            }
+            CaptureEvent::ToplevelAdded(t) | CaptureEvent::ToplevelChanged(t) => {
+                let source = Source::Toplevel(t.id.clone());
+                if self
+                    .tiles
+                    .iter()
+                    .any(|tile| tile.video.is_some() && tile.source == source)
+                {
+                    return;
+                }
+                let idx = self.tiles.iter().position(|tile| {
+                    tile.video.is_none()
+                        && tile.stale_toplevel.as_ref() != Some(&t.id)
+                        && matches!(&tile.follow, Some(FollowTarget::Toplevel(s)) if s.matches(&t))
+                });
+                if let Some(idx) = idx {
+                    self.resume_capture(client, idx, source);
+                }
+            }
+            CaptureEvent::OutputRemoved(_) | CaptureEvent::ToplevelClosed(_) => {}
+            CaptureEvent::Violation(v) => {
+                if self.check {
+                    eprintln!("Protocol violation: {v}");
+                    self.violations += 1;
+                }
             }
-            if video_size.1 > self.size.1 {
-                video_size.0 = video_size.0 * self.size.1 / video_size.1;
-                video_size.1 = self.size.1;
+        }
+    }
+
+    fn start_capture(&mut self, client: &mut CaptureClient, idx: usize) -> Result<(), Error> {
+        let tile = &mut self.tiles[idx];
+        tile.video = Some(client.create_session(&tile.source, self.options.clone())?);
+        // Later frames are requested by the frame callbacks of the video surface.
+        tile.frame_wanted = self.pacing == CliPacing::FrameCallback;
+        if let Some(options) = &self.cursor_options {
+            tile.cursor = Some(Cursor {
+                session: client.create_cursor_session(&tile.source, options.clone())?,
+                frame: None,
+                visible: false,
+                position: (0, 0),
+                hotspot: (0, 0),
+                pending_hotspot: (0, 0),
+            });
+        }
+        Ok(())
+    }
+
+    /// Destroys the sessions of a tile and shows a placeholder in its place.
+    fn stop_capture(&mut self, client: &mut CaptureClient, idx: usize) {
+        let tile = &mut self.tiles[idx];
+        if let Some(frame) = tile.video_frame.take() {
+            client.release(&frame);
+        }
+        if let Some(video) = tile.video.take() {
+            client.destroy_session(video);
+        }
+        if let Some(cursor) = tile.cursor.take() {
+            if let Some(frame) = &cursor.frame {
+                client.release(frame);
             }
-            obj.video_subsurface.set_position(
-                (self.size.0 - video_size.0) / 2,
-                (self.size.1 - video_size.1) / 2,
+            client.destroy_session(cursor.session);
+        }
+        if let Source::Toplevel(id) = &tile.source {
+            tile.stale_toplevel = Some(id.clone());
+        }
+        self.render_frame();
+    }
+
+    fn resume_capture(&mut self, client: &mut CaptureClient, idx: usize, source: Source) {
+        self.tiles[idx].source = source;
+        if let Err(e) = self.start_capture(client, idx) {
+            eprintln!("Could not capture the reappeared target: {e}");
+            self.stop_capture(client, idx);
+        }
+    }
+
+    fn video_frame_ready(&mut self, client: &mut CaptureClient, idx: usize, mut frame: Frame) {
+        let tile = &mut self.tiles[idx];
+        if let Some(stats) = &mut tile.stats {
+            stats.frame_ready(
+                frame.capture_time,
+                frame.ready_time,
+                frame.presentation_time,
             );
-            if video_size.0 > 0 && video_size.1 > 0 {
-                obj.video_viewport
-                    .set_destination(video_size.0, video_size.1);
+        }
+        if let Some(path) = self.screenshot.take() {
+            let res = read_rgba8(client, &frame, self.region).and_then(|(size, rgba)| {
+                screenshot::write_png(&path, size.0 as _, size.1 as _, &rgba)
+            });
+            client.release(&frame);
+            match res {
+                Ok(()) => self.running = false,
+                Err(e) => self.fail(Error::Output(format!(
+                    "Could not write {}: {e}",
+                    path.display()
+                ))),
+            }
+            return;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            match record_frame(client, recorder, &frame, self.region) {
+                Ok(true) => {}
+                Ok(false) => self.running = false,
+                Err(e) => {
+                    self.fail(Error::Output(format!("Could not record frame: {e}")));
+                    return;
+                }
             }
         }
-        obj.video_surface.commit();
-        obj.root_surface.attach(Some(&obj.root_buffer), 0, 0);
-        if self.size.0 > 0 && self.size.1 > 0 {
-            obj.root_viewport.set_destination(self.size.0, self.size.1);
+        let tile = &mut self.tiles[idx];
+        if let Some(old) = tile.video_frame.take() {
+            // Superseded before it was ever attached.
+            frame.damage.extend(&old.damage);
+            client.release(&old);
+        }
+        tile.video_frame = Some(frame);
+        self.render_frame();
+    }
+
+    /// Updates the cursor of the session without touching the videos.
+    fn move_cursor(&mut self, session: SessionId) {
+        let Some(obj) = self.window.as_mut().filter(|w| w.configured) else {
+            return;
+        };
+        let tile = self
+            .tiles
+            .iter_mut()
+            .zip(&mut obj.tiles)
+            .find(|(t, _)| t.cursor.as_ref().is_some_and(|c| c.session == session));
+        if let Some((tile, s)) = tile {
+            s.place_cursor(tile.cursor.as_mut());
+            obj.root_surface.commit();
+        }
+    }
+
+    fn render_frame(&mut self) {
+        let Some(obj) = self.window.as_mut().filter(|w| w.configured) else {
+            return;
+        };
+        for (idx, (tile, s)) in self.tiles.iter_mut().zip(&mut obj.tiles).enumerate() {
+            if let Some(frame) = tile.video_frame.take() {
+                if self.pacing == CliPacing::FrameCallback {
+                    s.video_surface.frame(&self.qhandle, idx);
+                }
+                let crop = match crop_rect(self.region, frame.size) {
+                    Ok(crop) => {
+                        s.region_outside = false;
+                        crop
+                    }
+                    Err(e) => {
+                        if !mem::replace(&mut s.region_outside, true) {
+                            eprintln!("Showing the whole frame: {e}");
+                        }
+                        None
+                    }
+                };
+                let bounds = crop.unwrap_or(Rect::new(0, 0, frame.size.0, frame.size.1));
+                let changed = frame.size != s.video_buffer_size
+                    || frame.transform != s.video_buffer_transform
+                    || crop != s.video_source;
+                s.video_surface.attach(Some(&frame.buffer), 0, 0);
+                if changed {
+                    s.video_surface
+                        .damage_buffer(bounds.x, bounds.y, bounds.width, bounds.height);
+                } else {
+                    for rect in frame.damage.rects() {
+                        let rect = rect.intersect(&bounds);
+                        if !rect.is_empty() {
+                            s.video_surface
+                                .damage_buffer(rect.x, rect.y, rect.width, rect.height);
+                        }
+                    }
+                }
+                if frame.transform != s.video_buffer_transform {
+                    s.video_surface.set_buffer_transform(frame.transform);
+                    s.video_buffer_transform = frame.transform;
+                }
+                if changed {
+                    match crop {
+                        Some(rect) => {
+                            let r = buffer_rect_to_surface(rect, frame.transform, frame.size);
+                            s.video_viewport.set_source(
+                                r.x as f64,
+                                r.y as f64,
+                                r.width as f64,
+                                r.height as f64,
+                            );
+                        }
+                        None => s.video_viewport.set_source(-1.0, -1.0, -1.0, -1.0),
+                    }
+                }
+                s.video_buffer_size = frame.size;
+                s.video_source = crop;
+            }
         }
+        // Without --stretch every cell is as large as the largest video, otherwise the
+        // window is divided evenly.
+        let (cols, rows) = grid_size(obj.tiles.len());
+        let cell = match self.fullscreen {
+            true => (self.size.0 / cols, self.size.1 / rows),
+            false => obj.tiles.iter().fold((1, 1), |cell, s| {
+                let size = s.size();
+                (cell.0.max(size.0), cell.1.max(size.1))
+            }),
+        };
+        for (idx, (tile, s)) in self.tiles.iter_mut().zip(&mut obj.tiles).enumerate() {
+            let origin = ((idx as i32 % cols) * cell.0, (idx as i32 / cols) * cell.1);
+            let buffer_size = s.size();
+            let mut video_size = buffer_size;
+            let mut video_pos = origin;
+            if self.fullscreen {
+                if video_size.0 != cell.0 {
+                    video_size.1 = video_size.1 * cell.0 / video_size.0;
+                    video_size.0 = cell.0;
+                }
+                if video_size.1 > cell.1 {
+                    video_size.0 = video_size.0 * cell.1 / video_size.1;
+                    video_size.1 = cell.1;
+                }
+                video_pos.0 += (cell.0 - video_size.0) / 2;
+                video_pos.1 += (cell.1 - video_size.1) / 2;
+                if video_size.0 > 0 && video_size.1 > 0 {
+                    s.video_viewport.set_destination(video_size.0, video_size.1);
+                }
+            }
+            s.video_subsurface.set_position(video_pos.0, video_pos.1);
+            if tile.video.is_none() {
+                s.video_surface.attach(None, 0, 0);
+            }
+            s.video_surface.commit();
+            s.video_area = Rect::new(video_pos.0, video_pos.1, video_size.0, video_size.1);
+            s.place_cursor(tile.cursor.as_mut());
+        }
+        obj.root_surface.attach(Some(&obj.root_buffer), 0, 0);
+        let grid = (cols * cell.0, rows * cell.1);
+        let root_size = match self.size {
+            (w, h) if w > 0 && h > 0 => (w, h),
+            // Covers the gaps between the videos and the placeholders of stopped sources.
+            _ if grid.0 > 0 && grid.1 > 0 => grid,
+            _ => (-1, -1),
+        };
+        obj.root_viewport.set_destination(root_size.0, root_size.1);
         obj.root_surface.commit();
     }
+
+    fn create_window(&self, qhandle: &QueueHandle<Self>) -> Result<Window, Error> {
+        let comp = global(&self.wl_compositor, "wl_compositor")?;
+        let wm_base = global(&self.wm_base, "xdg_wm_base")?;
+        let sub = global(&self.wl_subcompositor, "wl_subcompositor")?;
+        let viewporter = global(&self.wp_viewporter, "wp_viewporter")?;
+        let spbm = global(
+            &self.wp_single_pixel_buffer_manager,
+            "wp_single_pixel_buffer_manager_v1",
+        )?;
+        let root_surface = comp.create_surface(qhandle, ());
+        let root_viewport = viewporter.get_viewport(&root_surface, qhandle, ());
+        let root_buffer = spbm.create_u32_rgba_buffer(0, 0, 0, !0, qhandle, ());
+        let tiles = self
+            .tiles
+            .iter()
+            .map(|_| {
+                let video_surface = comp.create_surface(qhandle, ());
+                let video_subsurface =
+                    sub.get_subsurface(&video_surface, &root_surface, qhandle, ());
+                let video_viewport = viewporter.get_viewport(&video_surface, qhandle, ());
+                let cursor_surface = comp.create_surface(qhandle, ());
+                let cursor_subsurface =
+                    sub.get_subsurface(&cursor_surface, &root_surface, qhandle, ());
+                cursor_subsurface.place_above(&video_surface);
+                let cursor_viewport = viewporter.get_viewport(&cursor_surface, qhandle, ());
+                TileSurfaces {
+                    video_surface,
+                    video_subsurface,
+                    video_viewport,
+                    video_buffer_size: (1, 1),
+                    video_buffer_transform: Transform::Normal,
+                    video_source: None,
+                    video_area: Rect::new(0, 0, 1, 1),
+                    region_outside: false,
+                    cursor_surface,
+                    cursor_subsurface,
+                    cursor_viewport,
+                    cursor_buffer_size: (1, 1),
+                    cursor_attached: false,
+                }
+            })
+            .collect();
+        let xdg_surface = wm_base.get_xdg_surface(&root_surface, qhandle, ());
+        let xdg_toplevel = xdg_surface.get_toplevel(qhandle, ());
+        if let Some(decoman) = self.zxdg_decoration_manager_v1.as_ref() {
+            let decorations = decoman.get_toplevel_decoration(&xdg_toplevel, qhandle, ());
+            decorations.set_mode(zxdg_toplevel_decoration_v1::Mode::ServerSide);
+        }
+        root_surface.commit();
+        Ok(Window {
+            root_surface,
+            root_buffer,
+            root_viewport,
+            _xdg_surface: xdg_surface,
+            _xdg_toplevel: xdg_toplevel,
+            configured: false,
+            tiles,
+        })
+    }
+}
+
+/// Reads the part of a frame inside the region as RGBA8 and returns it with its size.
+fn read_rgba8(
+    client: &CaptureClient,
+    frame: &Frame,
+    region: Option<Rect>,
+) -> Result<((i32, i32), Vec<u8>), String> {
+    let rgba = client.read_rgba8(frame)?;
+    Ok(match crop_rect(region, frame.size)? {
+        Some(rect) => (
+            (rect.width, rect.height),
+            pixels::crop_rgba8(frame.size.0, &rgba, rect),
+        ),
+        None => (frame.size, rgba),
+    })
+}
+
+fn record_frame(
+    client: &CaptureClient,
+    recorder: &mut Recorder,
+    frame: &Frame,
+    region: Option<Rect>,
+) -> Result<bool, String> {
+    let (size, rgba) = read_rgba8(client, frame, region)?;
+    let yuv = pixels::rgba8_to_i420(size.0, size.1, &rgba);
+    let time = frame.presentation_time.unwrap_or(frame.ready_time);
+    recorder.push(time, size, yuv)
 }
 
 impl Dispatch<wl_registry::WlRegistry, ()> for State {
@@ -280,9 +1287,6 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                     state.zxdg_decoration_manager_v1 =
                         Some(registry.bind::<ZxdgDecorationManagerV1, _, _>(name, 1, qh, ()));
                 }
-                "wl_shm" => {
-                    state.wl_shm = Some(registry.bind::<WlShm, _, _>(name, 1, qh, ()));
-                }
                 "wp_viewporter" => {
                     state.wp_viewporter =
                         Some(registry.bind::<WpViewporter, _, _>(name, 1, qh, ()));
@@ -290,212 +1294,38 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                 "xdg_wm_base" => {
                     state.wm_base = Some(registry.bind::<XdgWmBase, _, _>(name, 1, qh, ()));
                 }
-                "wl_output" => {
-                    let o = registry.bind::<WlOutput, _, _>(name, 4, qh, ());
-                    state.outputs.insert(
-                        o.id(),
-                        Output {
-                            output: o,
-                            name: "".to_string(),
-                        },
-                    );
-                }
                 "wp_single_pixel_buffer_manager_v1" => {
                     state.wp_single_pixel_buffer_manager =
                         Some(registry.bind::<WpSinglePixelBufferManagerV1, _, _>(name, 1, qh, ()));
                 }
-                "ext_image_copy_capture_manager_v1" => {
-                    state.ext_image_copy_capture_manager_v1 =
-                        Some(registry.bind::<ExtImageCopyCaptureManagerV1, _, _>(name, 1, qh, ()));
-                }
-                "zwp_linux_dmabuf_v1" => {
-                    state.zwp_linux_dmabuf_v1 =
-                        Some(registry.bind::<ZwpLinuxDmabufV1, _, _>(name, 2, qh, ()));
-                }
-                "ext_output_image_capture_source_manager_v1" => {
-                    state.ext_output_image_capture_source_manager_v1 =
-                        Some(registry.bind::<ExtOutputImageCaptureSourceManagerV1, _, _>(
-                            name,
-                            1,
-                            qh,
-                            (),
-                        ));
-                }
-                "ext_foreign_toplevel_image_capture_source_manager_v1" => {
-                    state.ext_foreign_toplevel_image_capture_source_manager_v1 = Some(
-                        registry.bind::<ExtForeignToplevelImageCaptureSourceManagerV1, _, _>(
-                            name,
-                            1,
-                            qh,
-                            (),
-                        ),
-                    );
-                }
-                "ext_foreign_toplevel_list_v1" => {
-                    state.ext_foreign_toplevel_list_v1 =
-                        Some(registry.bind::<ExtForeignToplevelListV1, _, _>(name, 1, qh, ()));
-                }
                 _ => {}
             }
         }
     }
 }
 
-struct InitialRoundtrip;
-
-impl Dispatch<WlCallback, InitialRoundtrip> for State {
-    fn event(
-        state: &mut Self,
-        _proxy: &WlCallback,
-        _event: wl_callback::Event,
-        _data: &InitialRoundtrip,
-        _conn: &Connection,
-        qhandle: &QueueHandle<Self>,
-    ) {
-        state.display.sync(qhandle, SecondaryRoundtrip);
-    }
-}
-
-impl State {
-    fn print_toplevels(&self) {
-        let mut handles: Vec<_> = self.foreign_toplevels.values().collect();
-        handles.sort_by_cached_key(|t| &t.app_id);
-
-        eprintln!("Available toplevels:");
-        for handle in handles {
-            println!("  {} - {} - {}", handle.id, handle.app_id, handle.title);
-        }
-    }
-
-    fn print_outputs(&self) {
-        let mut outputs: Vec<_> = self.outputs.values().collect();
-        outputs.sort_by_cached_key(|t| &t.name);
-
-        eprintln!("Available outputs:");
-        for output in outputs {
-            println!("  {}", output.name);
-        }
-    }
-}
-
-struct SecondaryRoundtrip;
-
-impl Dispatch<WlCallback, SecondaryRoundtrip> for State {
-    fn event(
-        state: &mut Self,
-        _proxy: &WlCallback,
-        _event: wl_callback::Event,
-        _data: &SecondaryRoundtrip,
-        _conn: &Connection,
-        qhandle: &QueueHandle<Self>,
-    ) {
-        let source = match &state.target {
-            Target::None => {
-                state.print_outputs();
-                state.print_toplevels();
-                process::exit(0);
-            }
-            Target::Output(n) => {
-                let Some(o) = state.outputs.values().find(|o| &o.name == n) else {
-                    eprintln!("Unknown output {n}");
-                    state.print_outputs();
-                    process::exit(1);
-                };
-                let oicsm = state
-                    .ext_output_image_capture_source_manager_v1
-                    .as_ref()
-                    .expect("ext_output_image_capture_source_manager_v1");
-                oicsm.create_source(&o.output, qhandle, ())
-            }
-            Target::Toplevel(id) => {
-                let Some(o) = state.foreign_toplevels.values().find(|o| &o.id == id) else {
-                    eprintln!("Unknown toplevel {id}");
-                    state.print_toplevels();
-                    process::exit(1);
-                };
-                let fticsm = state
-                    .ext_foreign_toplevel_image_capture_source_manager_v1
-                    .as_ref()
-                    .expect("ext_foreign_toplevel_image_capture_source_manager_v1");
-                fticsm.create_source(&o.handle, qhandle, ())
-            }
-        };
-        let comp = state.wl_compositor.as_ref().expect("wl_compositor");
-        let wm_base = state.wm_base.as_ref().expect("wm_base");
-        let sub = state.wl_subcompositor.as_ref().expect("wl_subcompositor");
-        let viewporter = state.wp_viewporter.as_ref().expect("wp_viewporter");
-        let spbm = state
-            .wp_single_pixel_buffer_manager
-            .as_ref()
-            .expect("wp_single_pixel_buffer_manager");
-        let iccm = state
-            .ext_image_copy_capture_manager_v1
-            .as_ref()
-            .expect("ext_image_copy_capture_manager_v1");
-        let root_surface = comp.create_surface(qhandle, ());
-        let root_viewport = viewporter.get_viewport(&root_surface, qhandle, ());
-        let root_buffer = spbm.create_u32_rgba_buffer(0, 0, 0, !0, qhandle, None);
-        let video_surface = comp.create_surface(qhandle, ());
-        let video_subsurface = sub.get_subsurface(&video_surface, &root_surface, qhandle, ());
-        let video_viewport = viewporter.get_viewport(&video_surface, qhandle, ());
-        let xdg_surface = wm_base.get_xdg_surface(&root_surface, qhandle, ());
-        let xdg_toplevel = xdg_surface.get_toplevel(qhandle, ());
-        if let Some(decoman) = state.zxdg_decoration_manager_v1.as_ref() {
-            let decorations = decoman.get_toplevel_decoration(&xdg_toplevel, qhandle, ());
-            decorations.set_mode(zxdg_toplevel_decoration_v1::Mode::ServerSide);
-        }
-        root_surface.commit();
-        let session = iccm.create_session(&source, Options::all(), qhandle, ());
-        source.destroy();
-        state.objects = Some(Objects {
-            root_surface,
-            root_buffer,
-            root_viewport,
-            _xdg_surface: xdg_surface,
-            _xdg_toplevel: xdg_toplevel,
-            video_surface,
-            video_subsurface,
-            video_viewport,
-            video_buffer_size: (1, 1),
-            session,
-            frame: None,
-        });
-    }
-}
-
 delegate_noop!(State: ignore WlCompositor);
 delegate_noop!(State: ignore WlSurface);
+delegate_noop!(State: ignore WlBuffer);
 delegate_noop!(State: ignore WpViewporter);
 delegate_noop!(State: ignore WlSubsurface);
 delegate_noop!(State: ignore WpViewport);
 delegate_noop!(State: ignore WlSubcompositor);
 delegate_noop!(State: ignore ZxdgDecorationManagerV1);
 delegate_noop!(State: ignore ZxdgToplevelDecorationV1);
-delegate_noop!(State: ignore WlShm);
-delegate_noop!(State: ignore WlShmPool);
-delegate_noop!(State: ignore ExtImageCaptureSourceV1);
-delegate_noop!(State: ignore ExtOutputImageCaptureSourceManagerV1);
-delegate_noop!(State: ignore ExtForeignToplevelImageCaptureSourceManagerV1);
-delegate_noop!(State: ignore ExtImageCopyCaptureManagerV1);
 delegate_noop!(State: ignore WpSinglePixelBufferManagerV1);
-delegate_noop!(State: ignore ZwpLinuxDmabufV1);
-delegate_noop!(State: ignore ZwpLinuxBufferParamsV1);
 
-impl Dispatch<WlBuffer, Option<u64>> for State {
+impl Dispatch<WlCallback, usize> for State {
     fn event(
         state: &mut Self,
-        _proxy: &WlBuffer,
-        _event: wl_buffer::Event,
-        data: &Option<u64>,
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
+        _: &WlCallback,
+        event: wl_callback::Event,
+        idx: &usize,
+        _: &Connection,
+        _: &QueueHandle<Self>,
     ) {
-        if let Some(idx) = *data {
-            for buffer in &mut state.buffers {
-                if buffer.id == idx {
-                    buffer.free = true;
-                }
-            }
+        if let wl_callback::Event::Done { .. } = event {
+            state.tiles[*idx].frame_wanted = true;
         }
     }
 }
@@ -526,6 +1356,9 @@ impl Dispatch<XdgSurface, ()> for State {
     ) {
         if let xdg_surface::Event::Configure { serial, .. } = event {
             xdg_surface.ack_configure(serial);
+            if let Some(window) = &mut state.window {
+                window.configured = true;
+            }
             state.render_frame();
         }
     }
@@ -553,269 +1386,3 @@ impl Dispatch<XdgToplevel, ()> for State {
         }
     }
 }
-
-impl State {
-    fn capture_frame(&mut self, qh: &QueueHandle<Self>) {
-        let Some(obj) = &mut self.objects else {
-            return;
-        };
-        if obj.frame.is_some() {
-            return;
-        }
-        if self.capture_size.0 == 0 || self.capture_size.1 == 0 {
-            return;
-        }
-        self.buffers.retain(|b| {
-            let retain = b.size == self.capture_size;
-            if !retain {
-                b.buffer.destroy();
-            }
-            retain
-        });
-        let mut bo_opt = None;
-        let b = self.buffers.iter_mut().find(|b| b.free);
-        let b = match b {
-            Some(b) => b,
-            _ => {
-                let buffer = if self.dmabuf {
-                    let bo = self
-                        .gbm
-                        .as_ref()
-                        .unwrap()
-                        .create_buffer_object_with_modifiers2::<()>(
-                            self.capture_size.0 as _,
-                            self.capture_size.1 as _,
-                            Xrgb8888,
-                            self.dmabuf_modifiers.iter().map(|&m| m.into()),
-                            BufferObjectFlags::RENDERING,
-                        )
-                        .expect("allocate dmabuf");
-                    let dmabuf = self
-                        .zwp_linux_dmabuf_v1
-                        .as_ref()
-                        .expect("zwp_linux_dmabuf_v1");
-                    let params = dmabuf.create_params(qh, ());
-                    for i in 0..bo.plane_count().expect("plane_count") {
-                        let modifier: u64 = bo.modifier().unwrap().into();
-                        params.add(
-                            bo.fd_for_plane(i as _).unwrap().as_fd(),
-                            i,
-                            bo.offset(i as _).unwrap(),
-                            bo.stride_for_plane(i as _).unwrap(),
-                            (modifier >> 32) as _,
-                            modifier as _,
-                        );
-                    }
-                    let buffer = params.create_immed(
-                        self.capture_size.0,
-                        self.capture_size.1,
-                        Xrgb8888 as _,
-                        Flags::empty(),
-                        qh,
-                        Some(self.next_buffer_id),
-                    );
-                    params.destroy();
-                    bo_opt = Some(bo);
-                    buffer
-                } else {
-                    let memfile = memfile::MemFile::create_sealable("wl_shm").unwrap();
-                    let size = self.capture_size.0 * self.capture_size.1 * 4;
-                    memfile.set_len(size as _).unwrap();
-                    memfile.add_seal(Seal::Shrink).unwrap();
-                    let shm = self.wl_shm.as_ref().expect("wl_shm");
-                    let pool = shm.create_pool(memfile.as_fd(), size, qh, ());
-                    let buffer = pool.create_buffer(
-                        0,
-                        self.capture_size.0,
-                        self.capture_size.1,
-                        self.capture_size.0 * 4,
-                        Format::Argb8888,
-                        qh,
-                        Some(self.next_buffer_id),
-                    );
-                    pool.destroy();
-                    buffer
-                };
-                let b = Buffer {
-                    id: self.next_buffer_id,
-                    buffer,
-                    free: true,
-                    ready: false,
-                    size: self.capture_size,
-                    _bo_opt: bo_opt,
-                };
-                self.next_buffer_id += 1;
-                self.buffers.push(b);
-                self.buffers.last_mut().unwrap()
-            }
-        };
-        let frame = obj.session.create_frame(qh, b.id);
-        frame.attach_buffer(&b.buffer);
-        frame.damage_buffer(0, 0, b.size.0, b.size.1);
-        frame.capture();
-        obj.frame = Some(frame);
-    }
-}
-
-impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for State {
-    fn event(
-        state: &mut Self,
-        _: &ExtImageCopyCaptureSessionV1,
-        event: ext_image_copy_capture_session_v1::Event,
-        _: &(),
-        _: &Connection,
-        qh: &QueueHandle<Self>,
-    ) {
-        use ext_image_copy_capture_session_v1::Event;
-
-        match event {
-            Event::BufferSize { width, height } => {
-                state.capture_size = (width as _, height as _);
-            }
-            Event::DmabufDevice { device } => {
-                state.dmabuf_device = bytemuck::pod_read_unaligned(&device);
-                if state.dmabuf {
-                    let dev = drm::node::dev_path(state.dmabuf_device, NodeType::Render)
-                        .expect("dmabuf device");
-                    let fd = File::options()
-                        .read(true)
-                        .write(true)
-                        .open(dev)
-                        .expect("dmabuf device");
-                    let gbm = gbm::Device::new(fd).expect("gbm");
-                    state.gbm = Some(gbm);
-                }
-            }
-            Event::DmabufFormat { format, modifiers } => {
-                if format == Xrgb8888 as u32 {
-                    state.dmabuf_modifiers = bytemuck::pod_collect_to_vec(&modifiers);
-                }
-            }
-            Event::Stopped => {
-                state.running = false;
-            }
-            Event::Done => {
-                state.capture_frame(qh);
-            }
-            _ => {}
-        }
-    }
-}
-
-impl Dispatch<ExtImageCopyCaptureFrameV1, u64> for State {
-    fn event(
-        state: &mut Self,
-        frame: &ExtImageCopyCaptureFrameV1,
-        event: ext_image_copy_capture_frame_v1::Event,
-        id: &u64,
-        _: &Connection,
-        qh: &QueueHandle<Self>,
-    ) {
-        use ext_image_copy_capture_frame_v1::Event;
-
-        let Some(obj) = &mut state.objects else {
-            return;
-        };
-        let Some(buffer) = state.buffers.iter_mut().find(|b| b.id == *id) else {
-            return;
-        };
-
-        match event {
-            Event::Transform { .. } => {}
-            Event::Damage { .. } => {}
-            Event::PresentationTime { .. } => {}
-            Event::Ready => {
-                buffer.ready = true;
-                obj.frame.take();
-                state.render_frame();
-                frame.destroy();
-                state.capture_frame(qh);
-            }
-            Event::Failed { reason } => {
-                eprintln!("failed: {:?}", reason);
-                obj.frame.take();
-                frame.destroy();
-                state.capture_frame(qh);
-            }
-            _ => {}
-        }
-    }
-}
-
-impl Dispatch<ExtForeignToplevelListV1, ()> for State {
-    fn event(
-        _: &mut Self,
-        _: &ExtForeignToplevelListV1,
-        _: ext_foreign_toplevel_list_v1::Event,
-        _: &(),
-        _: &Connection,
-        _: &QueueHandle<Self>,
-    ) {
-    }
-
-    event_created_child!(State, ExtForeignToplevelListV1, [
-        EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ()),
-    ]);
-}
-
-impl Dispatch<ExtForeignToplevelHandleV1, ()> for State {
-    fn event(
-        state: &mut Self,
-        handle: &ExtForeignToplevelHandleV1,
-        event: ext_foreign_toplevel_handle_v1::Event,
-        _: &(),
-        _: &Connection,
-        _: &QueueHandle<Self>,
-    ) {
-        use ext_foreign_toplevel_handle_v1::Event;
-
-        let tl = state
-            .foreign_toplevels
-            .entry(handle.id())
-            .or_insert_with(|| ForeignToplevel {
-                handle: handle.clone(),
-                id: "".to_string(),
-                title: "".to_string(),
-                app_id: "".to_string(),
-            });
-
-        match event {
-            Event::Closed => {
-                state.foreign_toplevels.remove(&handle.id());
-                handle.destroy();
-            }
-            Event::Title { title } => {
-                tl.title = title;
-            }
-            Event::AppId { app_id } => {
-                tl.app_id = app_id;
-            }
-            Event::Identifier { identifier } => {
-                tl.id = identifier;
-            }
-            _ => {}
-        }
-    }
-}
-
-impl Dispatch<WlOutput, ()> for State {
-    fn event(
-        state: &mut Self,
-        output: &WlOutput,
-        event: wl_output::Event,
-        _: &(),
-        _: &Connection,
-        _: &QueueHandle<Self>,
-    ) {
-        use wl_output::Event;
-
-        let o = state.outputs.get_mut(&output.id()).unwrap();
-
-        match event {
-            Event::Name { name } => {
-                o.name = name;
-            }
-            _ => {}
-        }
-    }
-}
@@ -0,0 +1,230 @@
+use {crate::damage::Rect, wayland_client::protocol::wl_shm::Format};
+
+pub struct ShmFormat {
+    pub name: &'static str,
+    pub format: Format,
+    pub bpp: i32,
+    decode: fn(&[u8]) -> [u8; 4],
+}
+
+/// The shm formats this client can read, in order of preference.
+pub static SHM_FORMATS: &[ShmFormat] = &[
+    ShmFormat {
+        name: "xrgb8888",
+        format: Format::Xrgb8888,
+        bpp: 4,
+        decode: |p| [p[2], p[1], p[0], 0xff],
+    },
+    ShmFormat {
+        name: "argb8888",
+        format: Format::Argb8888,
+        bpp: 4,
+        decode: |p| [p[2], p[1], p[0], p[3]],
+    },
+    ShmFormat {
+        name: "xbgr8888",
+        format: Format::Xbgr8888,
+        bpp: 4,
+        decode: |p| [p[0], p[1], p[2], 0xff],
+    },
+    ShmFormat {
+        name: "abgr8888",
+        format: Format::Abgr8888,
+        bpp: 4,
+        decode: |p| [p[0], p[1], p[2], p[3]],
+    },
+    ShmFormat {
+        name: "xrgb2101010",
+        format: Format::Xrgb2101010,
+        bpp: 4,
+        decode: |p| {
+            let [r, g, b, _] = unpack_2101010(p);
+            [r, g, b, 0xff]
+        },
+    },
+    ShmFormat {
+        name: "argb2101010",
+        format: Format::Argb2101010,
+        bpp: 4,
+        decode: unpack_2101010,
+    },
+    ShmFormat {
+        name: "xbgr2101010",
+        format: Format::Xbgr2101010,
+        bpp: 4,
+        decode: |p| {
+            let [b, g, r, _] = unpack_2101010(p);
+            [r, g, b, 0xff]
+        },
+    },
+    ShmFormat {
+        name: "abgr2101010",
+        format: Format::Abgr2101010,
+        bpp: 4,
+        decode: |p| {
+            let [b, g, r, a] = unpack_2101010(p);
+            [r, g, b, a]
+        },
+    },
+    ShmFormat {
+        name: "xbgr16161616",
+        format: Format::Xbgr16161616,
+        bpp: 8,
+        decode: |p| [p[1], p[3], p[5], 0xff],
+    },
+    ShmFormat {
+        name: "abgr16161616",
+        format: Format::Abgr16161616,
+        bpp: 8,
+        decode: |p| [p[1], p[3], p[5], p[7]],
+    },
+    ShmFormat {
+        name: "rgbx8888",
+        format: Format::Rgbx8888,
+        bpp: 4,
+        decode: |p| [p[3], p[2], p[1], 0xff],
+    },
+    ShmFormat {
+        name: "rgba8888",
+        format: Format::Rgba8888,
+        bpp: 4,
+        decode: |p| [p[3], p[2], p[1], p[0]],
+    },
+    ShmFormat {
+        name: "bgrx8888",
+        format: Format::Bgrx8888,
+        bpp: 4,
+        decode: |p| [p[1], p[2], p[3], 0xff],
+    },
+    ShmFormat {
+        name: "bgra8888",
+        format: Format::Bgra8888,
+        bpp: 4,
+        decode: |p| [p[1], p[2], p[3], p[0]],
+    },
+    ShmFormat {
+        name: "rgb888",
+        format: Format::Rgb888,
+        bpp: 3,
+        decode: |p| [p[2], p[1], p[0], 0xff],
+    },
+    ShmFormat {
+        name: "bgr888",
+        format: Format::Bgr888,
+        bpp: 3,
+        decode: |p| [p[0], p[1], p[2], 0xff],
+    },
+    ShmFormat {
+        name: "rgb565",
+        format: Format::Rgb565,
+        bpp: 2,
+        decode: |p| {
+            let v = u16::from_le_bytes([p[0], p[1]]) as u32;
+            let r = (v >> 11) & 0x1f;
+            let g = (v >> 5) & 0x3f;
+            let b = v & 0x1f;
+            [
+                (r * 255 / 31) as u8,
+                (g * 255 / 63) as u8,
+                (b * 255 / 31) as u8,
+                0xff,
+            ]
+        },
+    },
+];
+
+/// Unpacks `[31:0] A:R:G:B 2:10:10:10` into 8-bit `[r, g, b, a]`.
+fn unpack_2101010(p: &[u8]) -> [u8; 4] {
+    let v = u32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+    [
+        (v >> 22) as u8,
+        (v >> 12) as u8,
+        (v >> 2) as u8,
+        ((v >> 30) * 0x55) as u8,
+    ]
+}
+
+pub fn shm_format(format: Format) -> Option<&'static ShmFormat> {
+    SHM_FORMATS.iter().find(|f| f.format == format)
+}
+
+pub fn parse_shm_format(name: &str) -> Result<Format, String> {
+    SHM_FORMATS
+        .iter()
+        .find(|f| f.name.eq_ignore_ascii_case(name))
+        .map(|f| f.format)
+        .ok_or_else(|| {
+            let names: Vec<_> = SHM_FORMATS.iter().map(|f| f.name).collect();
+            format!("expected one of {}", names.join(", "))
+        })
+}
+
+/// Converts the contents of an shm buffer to tightly packed RGBA8 pixels.
+///
+/// Returns `None` if the format is not supported.
+pub fn to_rgba8(
+    format: Format,
+    width: i32,
+    height: i32,
+    stride: i32,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    let info = shm_format(format)?;
+    let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height as usize {
+        let row = &data[y * stride as usize..][..(width * info.bpp) as usize];
+        for px in row.chunks_exact(info.bpp as usize) {
+            rgba.extend_from_slice(&(info.decode)(px));
+        }
+    }
+    Some(rgba)
+}
+
+/// Copies the pixels inside `rect` out of tightly packed RGBA8 pixels.
+pub fn crop_rgba8(width: i32, rgba: &[u8], rect: Rect) -> Vec<u8> {
+    let mut cropped = Vec::with_capacity(rect.width as usize * rect.height as usize * 4);
+    for y in rect.y..rect.y + rect.height {
+        let start = (y * width + rect.x) as usize * 4;
+        cropped.extend_from_slice(&rgba[start..][..rect.width as usize * 4]);
+    }
+    cropped
+}
+
+/// Converts RGBA8 pixels to planar YUV 4:2:0 using full-range BT.601 as in JPEG.
+pub fn rgba8_to_i420(width: i32, height: i32, rgba: &[u8]) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+    let mut yuv = vec![0; w * h + 2 * cw * ch];
+    let (y_plane, chroma) = yuv.split_at_mut(w * h);
+    let (u_plane, v_plane) = chroma.split_at_mut(cw * ch);
+    let rgb = |x: usize, y: usize| {
+        let px = &rgba[(y * w + x) * 4..][..3];
+        (px[0] as i32, px[1] as i32, px[2] as i32)
+    };
+    for y in 0..h {
+        for x in 0..w {
+            let (r, g, b) = rgb(x, y);
+            y_plane[y * w + x] = ((77 * r + 150 * g + 29 * b + 128) >> 8) as u8;
+        }
+    }
+    for cy in 0..ch {
+        for cx in 0..cw {
+            let (mut r, mut g, mut b, mut n) = (0, 0, 0, 0);
+            for y in 2 * cy..(2 * cy + 2).min(h) {
+                for x in 2 * cx..(2 * cx + 2).min(w) {
+                    let px = rgb(x, y);
+                    r += px.0;
+                    g += px.1;
+                    b += px.2;
+                    n += 1;
+                }
+            }
+            let (r, g, b) = (r / n, g / n, b / n);
+            u_plane[cy * cw + cx] =
+                (128 + ((-43 * r - 85 * g + 128 * b + 128) >> 8)).min(255) as u8;
+            v_plane[cy * cw + cx] =
+                (128 + ((128 * r - 107 * g - 21 * b + 128) >> 8)).min(255) as u8;
+        }
+    }
+    yuv
+}
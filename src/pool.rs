@@ -0,0 +1,133 @@
+use {
+    crate::{damage::Damage, pixels},
+    memfile::MemFile,
+    std::{mem, os::unix::fs::FileExt, time::Duration},
+    wayland_client::protocol::{wl_buffer::WlBuffer, wl_output::Transform, wl_shm::Format},
+};
+
+pub struct Buffer {
+    pub id: u64,
+    pub buffer: WlBuffer,
+    pub free: bool,
+    pub size: (i32, i32),
+    pub format: BufferFormat,
+    pub shm: Option<ShmStorage>,
+    pub capture_time: Duration,
+    pub presentation_time: Option<Duration>,
+    pub transform: Transform,
+    pub damage: Damage,
+    pub frame_damage: Damage,
+    pub _bo_opt: Option<gbm::BufferObject<()>>,
+}
+
+pub struct ShmStorage {
+    pub memfile: MemFile,
+    pub stride: i32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BufferFormat {
+    Shm(Format),
+    Dmabuf(gbm::Format),
+}
+
+impl Buffer {
+    pub fn read_rgba8(&self) -> Result<Vec<u8>, String> {
+        let (Some(shm), BufferFormat::Shm(format)) = (&self.shm, self.format) else {
+            return Err("buffer is not an shm buffer".to_string());
+        };
+        let mut data = vec![0; (shm.stride * self.size.1) as usize];
+        shm.memfile
+            .read_exact_at(&mut data, 0)
+            .map_err(|e| e.to_string())?;
+        pixels::to_rgba8(format, self.size.0, self.size.1, shm.stride, &data)
+            .ok_or_else(|| format!("unsupported shm format {:?}", format))
+    }
+}
+
+/// The buffers of one capture session.
+///
+/// A session can only have one frame at a time, so pipelining happens between the
+/// capture and the consumer: while the consumer holds the latest frame, the next one
+/// is captured into another buffer. Buffers are only reused after the consumer has
+/// released them, and at most `depth` buffers are allocated.
+pub struct BufferPool {
+    depth: usize,
+    buffers: Vec<Buffer>,
+}
+
+impl BufferPool {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            buffers: vec![],
+        }
+    }
+
+    /// Destroys all buffers that don't have the given size and format.
+    pub fn retain_compatible(&mut self, size: (i32, i32), format: BufferFormat) {
+        self.buffers.retain(|b| {
+            let retain = b.size == size && b.format == format;
+            if !retain {
+                b.buffer.destroy();
+            }
+            retain
+        });
+    }
+
+    /// Destroys all buffers.
+    pub fn clear(&mut self) {
+        for buffer in self.buffers.drain(..) {
+            buffer.buffer.destroy();
+        }
+    }
+
+    /// Returns whether a new buffer has to be allocated before the next capture.
+    pub fn needs_allocation(&self) -> bool {
+        self.buffers.len() < self.depth && !self.buffers.iter().any(|b| b.free)
+    }
+
+    pub fn insert(&mut self, buffer: Buffer) {
+        self.buffers.push(buffer);
+    }
+
+    /// Returns a buffer that can be captured into, if any.
+    pub fn acquire(&mut self) -> Option<&mut Buffer> {
+        self.buffers.iter_mut().find(|b| b.free)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Buffer> {
+        self.buffers.iter().find(|b| b.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Buffer> {
+        self.buffers.iter_mut().find(|b| b.id == id)
+    }
+
+    /// Marks a buffer as released by the consumer. Returns whether it belongs to this pool.
+    pub fn release(&mut self, id: u64) -> bool {
+        match self.get_mut(id) {
+            Some(buffer) => {
+                buffer.free = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Distributes the damage of a frame that has become ready to the other buffers and
+    /// returns it.
+    pub fn accumulate_damage(&mut self, id: u64) -> Damage {
+        let Some(idx) = self.buffers.iter().position(|b| b.id == id) else {
+            return Damage::default();
+        };
+        let damage = mem::take(&mut self.buffers[idx].frame_damage);
+        for buffer in &mut self.buffers {
+            if buffer.id != id {
+                buffer.damage.extend(&damage);
+            }
+        }
+        self.buffers[idx].damage.clear();
+        damage
+    }
+}
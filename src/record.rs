@@ -0,0 +1,109 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// Writes captured frames to a YUV4MPEG2 stream.
+///
+/// Y4M streams have a constant frame rate. Frames are placed on that grid according
+/// to their presentation time, repeating the previous frame to fill gaps, so that the
+/// file reflects the cadence at which the compositor produced them.
+pub struct Recorder {
+    file: BufWriter<File>,
+    fps: u32,
+    max_frames: Option<u64>,
+    max_duration: Option<Duration>,
+    size: Option<(i32, i32)>,
+    start: Option<Duration>,
+    captured: u64,
+    written: u64,
+    pending: Option<Vec<u8>>,
+}
+
+impl Recorder {
+    pub fn create(
+        path: &Path,
+        fps: u32,
+        max_frames: Option<u64>,
+        max_duration: Option<Duration>,
+    ) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            fps,
+            max_frames,
+            max_duration,
+            size: None,
+            start: None,
+            captured: 0,
+            written: 0,
+            pending: None,
+        })
+    }
+
+    /// Adds an I420 frame presented at `time`.
+    ///
+    /// Returns `false` once the recording has reached its frame or duration limit.
+    pub fn push(&mut self, time: Duration, size: (i32, i32), yuv: Vec<u8>) -> Result<bool, String> {
+        if self.max_frames.is_some_and(|max| self.captured >= max) {
+            return Ok(false);
+        }
+        match self.size {
+            None => {
+                writeln!(
+                    self.file,
+                    "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg XCOLORRANGE=FULL",
+                    size.0, size.1, self.fps
+                )
+                .map_err(|e| e.to_string())?;
+                self.size = Some(size);
+            }
+            Some(s) if s != size => {
+                return Err(format!(
+                    "capture size changed from {}x{} to {}x{}",
+                    s.0, s.1, size.0, size.1
+                ));
+            }
+            _ => {}
+        }
+        let start = *self.start.get_or_insert(time);
+        let elapsed = time.saturating_sub(start);
+        if self.max_duration.is_some_and(|max| elapsed >= max) {
+            return Ok(false);
+        }
+        let index = (elapsed.as_secs_f64() * self.fps as f64).round() as u64;
+        if let Some(pending) = self.pending.take() {
+            // The pending frame occupies every slot up to the new one. A frame that
+            // lands on the same slot as its predecessor replaces it.
+            for _ in self.written..index {
+                self.write_frame(&pending)?;
+            }
+        }
+        self.pending = Some(yuv);
+        self.captured += 1;
+        Ok(self.max_frames.is_none_or(|max| self.captured < max))
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(pending) = self.pending.take() {
+            self.write_frame(&pending)?;
+        }
+        self.file.flush().map_err(|e| e.to_string())?;
+        eprintln!(
+            "Recorded {} frames ({} captured)",
+            self.written, self.captured
+        );
+        Ok(())
+    }
+
+    fn write_frame(&mut self, yuv: &[u8]) -> Result<(), String> {
+        self.file
+            .write_all(b"FRAME\n")
+            .and_then(|_| self.file.write_all(yuv))
+            .map_err(|e| e.to_string())?;
+        self.written += 1;
+        Ok(())
+    }
+}
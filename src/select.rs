@@ -0,0 +1,92 @@
+use {crate::client::ForeignToplevel, regex::Regex};
+
+/// A glob or, if enclosed in slashes, a regular expression.
+///
+/// Globs support `*` and `?` and must match the whole string. Regular expressions
+/// match anywhere in the string unless anchored.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn matches(&self, s: &str) -> bool {
+        self.regex.is_match(s)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+/// Returns a pattern that only matches `s` itself.
+pub fn literal(s: &str) -> Pattern {
+    Pattern {
+        source: s.to_string(),
+        regex: Regex::new(&format!("^{}$", regex::escape(s))).unwrap(),
+    }
+}
+
+pub fn parse_pattern(pattern: &str) -> Result<Pattern, String> {
+    let regex = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+        Some(re) => re.to_string(),
+        None => glob_to_regex(pattern),
+    };
+    Ok(Pattern {
+        source: pattern.to_string(),
+        regex: Regex::new(&regex).map_err(|e| e.to_string())?,
+    })
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = "^".to_string();
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// What to do when more than one toplevel matches.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Pick {
+    /// The toplevel that was announced first.
+    First,
+    /// The toplevel that was announced last.
+    Newest,
+    /// Fail if the selection is ambiguous.
+    #[default]
+    Error,
+}
+
+/// Selects toplevels by app_id and title. Unset patterns match everything.
+#[derive(Clone, Debug, Default)]
+pub struct ToplevelSelector {
+    pub app_id: Option<Pattern>,
+    pub title: Option<Pattern>,
+}
+
+impl ToplevelSelector {
+    pub fn matches(&self, toplevel: &ForeignToplevel) -> bool {
+        let matches = |p: &Option<Pattern>, s: &str| p.as_ref().is_none_or(|p| p.matches(s));
+        matches(&self.app_id, &toplevel.app_id) && matches(&self.title, &toplevel.title)
+    }
+}
+
+impl std::fmt::Display for ToplevelSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(p) = &self.app_id {
+            parts.push(format!("app_id {}", p.as_str()));
+        }
+        if let Some(p) = &self.title {
+            parts.push(format!("title {}", p.as_str()));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
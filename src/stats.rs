@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+/// Collects per-frame timing information.
+///
+/// Latency is measured from the `capture` request to the `ready` event. Frame intervals
+/// are taken from presentation times if the compositor sends them and from the ready
+/// times otherwise.
+#[derive(Default)]
+pub struct Stats {
+    report_interval: Option<Duration>,
+    first: Option<Duration>,
+    last: Option<Duration>,
+    latencies: Vec<Duration>,
+    intervals: Vec<Duration>,
+    failed: u64,
+    window_start: Option<Duration>,
+    window_frames: u64,
+    window_latency: Duration,
+    window_failed: u64,
+}
+
+pub struct Summary {
+    pub frames: u64,
+    pub failed: u64,
+    pub fps: f64,
+    pub latency: Percentiles,
+    pub interval: Percentiles,
+    pub jitter: Duration,
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+pub struct Percentiles {
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    pub fn new(report_interval: Option<Duration>) -> Self {
+        Self {
+            report_interval,
+            ..Default::default()
+        }
+    }
+
+    pub fn frame_ready(
+        &mut self,
+        captured: Duration,
+        ready: Duration,
+        presented: Option<Duration>,
+    ) {
+        let latency = ready.saturating_sub(captured);
+        let time = presented.unwrap_or(ready);
+        if let Some(last) = self.last {
+            self.intervals.push(time.saturating_sub(last));
+        }
+        self.first.get_or_insert(time);
+        self.last = Some(time);
+        self.latencies.push(latency);
+        self.report(ready, latency);
+    }
+
+    pub fn frame_failed(&mut self) {
+        self.failed += 1;
+        self.window_failed += 1;
+    }
+
+    fn report(&mut self, now: Duration, latency: Duration) {
+        let Some(interval) = self.report_interval else {
+            return;
+        };
+        let start = *self.window_start.get_or_insert(now);
+        self.window_frames += 1;
+        self.window_latency += latency;
+        let elapsed = now.saturating_sub(start);
+        if elapsed < interval {
+            return;
+        }
+        eprintln!(
+            "{:.1} fps, mean latency {:.2} ms, {} failed",
+            self.window_frames as f64 / elapsed.as_secs_f64(),
+            ms(self.window_latency / self.window_frames as u32),
+            self.window_failed,
+        );
+        self.window_start = Some(now);
+        self.window_frames = 0;
+        self.window_latency = Duration::ZERO;
+        self.window_failed = 0;
+    }
+
+    pub fn summary(&self) -> Summary {
+        let frames = self.latencies.len() as u64;
+        let elapsed = match (self.first, self.last) {
+            (Some(first), Some(last)) => last.saturating_sub(first),
+            _ => Duration::ZERO,
+        };
+        let fps = match self.intervals.len() {
+            0 => 0.0,
+            n if elapsed.is_zero() => n as f64,
+            n => n as f64 / elapsed.as_secs_f64(),
+        };
+        let interval = percentiles(&self.intervals);
+        let jitter = match self.intervals.len() {
+            0 => Duration::ZERO,
+            n => {
+                let mean = interval.mean.as_secs_f64();
+                let var = self
+                    .intervals
+                    .iter()
+                    .map(|i| (i.as_secs_f64() - mean).powi(2))
+                    .sum::<f64>()
+                    / n as f64;
+                Duration::from_secs_f64(var.sqrt())
+            }
+        };
+        // The median interval is taken as the nominal frame period. Every interval that
+        // spans several periods is assumed to have skipped the frames in between.
+        let dropped = match interval.p50.as_secs_f64() {
+            p if p > 0.0 => self
+                .intervals
+                .iter()
+                .map(|i| (i.as_secs_f64() / p).round().max(1.0) as u64 - 1)
+                .sum(),
+            _ => 0,
+        };
+        Summary {
+            frames,
+            failed: self.failed,
+            fps,
+            latency: percentiles(&self.latencies),
+            interval,
+            jitter,
+            dropped,
+        }
+    }
+
+    pub fn print_summary(&self) {
+        let s = self.summary();
+        eprintln!("Frames: {} ready, {} failed", s.frames, s.failed);
+        if s.frames == 0 {
+            return;
+        }
+        eprintln!("Frame rate: {:.2} fps", s.fps);
+        eprintln!("Latency (ms): {}", s.latency);
+        eprintln!("Interval (ms): {}", s.interval);
+        eprintln!("Jitter: {:.3} ms", ms(s.jitter));
+        eprintln!("Dropped (estimated): {}", s.dropped);
+    }
+}
+
+impl std::fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "min {:.3}, mean {:.3}, p50 {:.3}, p95 {:.3}, p99 {:.3}, max {:.3}",
+            ms(self.min),
+            ms(self.mean),
+            ms(self.p50),
+            ms(self.p95),
+            ms(self.p99),
+            ms(self.max),
+        )
+    }
+}
+
+fn percentiles(values: &[Duration]) -> Percentiles {
+    if values.is_empty() {
+        return Percentiles::default();
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort();
+    let at = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+    Percentiles {
+        min: sorted[0],
+        mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+        p50: at(0.50),
+        p95: at(0.95),
+        p99: at(0.99),
+        max: sorted[sorted.len() - 1],
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,584 @@
+//! Drives [`CaptureClient`] and the viewer against the mock compositor in `mock`.
+
+mod mock;
+
+use {
+    mock::{Config, Mock, Target, CURSOR_POSITION, CURSOR_SIZE},
+    std::{
+        os::{fd::AsRawFd, unix::process::CommandExt},
+        process::Command,
+        thread,
+        time::Duration,
+    },
+    wayland_client::protocol::wl_shm::Format,
+    wayland_copy_capture_test_client::{
+        damage::Rect,
+        monotonic_now,
+        select::{self, Pick, ToplevelSelector},
+        BufferFormat, CaptureClient, CaptureEvent, Error, Frame, Pacing, SessionId, SessionOptions,
+        Source,
+    },
+    wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_frame_v1::FailureReason,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn connect(config: Config) -> (Mock, CaptureClient) {
+    let (mock, conn) = Mock::start(config);
+    (mock, CaptureClient::new(conn).unwrap())
+}
+
+fn output() -> Source {
+    Source::Output("DP-1".to_string())
+}
+
+/// Returns the next event or `None` if there was none within `timeout`.
+fn next_event(
+    client: &mut CaptureClient,
+    timeout: Duration,
+) -> Result<Option<CaptureEvent>, Error> {
+    let deadline = monotonic_now() + timeout;
+    loop {
+        client.dispatch_pending()?;
+        if let Some(event) = client.next_event() {
+            return Ok(Some(event));
+        }
+        if monotonic_now() >= deadline {
+            return Ok(None);
+        }
+        client.read_events(Some(deadline))?;
+    }
+}
+
+/// Waits for the next frame of the session, skipping other events.
+fn next_frame(client: &mut CaptureClient, id: SessionId) -> Result<Frame, Error> {
+    loop {
+        match next_event(client, TIMEOUT)?.expect("timed out waiting for a frame") {
+            CaptureEvent::Frame(frame) if frame.session == id => return Ok(frame),
+            CaptureEvent::Frame(frame) => client.release(&frame),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn lists_outputs_toplevels_and_source_managers() {
+    let (_mock, client) = connect(Config::default());
+    let outputs: Vec<_> = client.outputs().collect();
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(outputs[0].name, "DP-1");
+    assert_eq!(outputs[0].description, "Dell U2720Q (DP-1)");
+    assert_eq!(outputs[0].mode.map(|m| (m.width, m.height)), Some((64, 48)));
+    let mut toplevels: Vec<_> = client.toplevels().map(|t| &t.app_id[..]).collect();
+    toplevels.sort();
+    assert_eq!(toplevels, ["firefox", "foot"]);
+    assert_eq!(
+        client.source_managers(),
+        [
+            "ext_output_image_capture_source_manager_v1",
+            "ext_foreign_toplevel_image_capture_source_manager_v1",
+        ]
+    );
+}
+
+#[test]
+fn selects_outputs_by_name_description_make_or_model() {
+    let config = Config {
+        outputs: vec![
+            mock::output("DP-1", "Dell U2720Q", (64, 48)),
+            mock::output("DP-2", "LG 27GL850", (64, 48)),
+        ],
+        ..Default::default()
+    };
+    let (_mock, client) = connect(config);
+    let find = |p: &str| {
+        client
+            .find_output(&select::parse_pattern(p).unwrap())
+            .map(|o| o.name.clone())
+    };
+    assert_eq!(find("DP-2").unwrap(), "DP-2");
+    assert_eq!(find("Dell*").unwrap(), "DP-1");
+    assert_eq!(find("/27GL/").unwrap(), "DP-2");
+    assert!(
+        matches!(find("DP-*"), Err(Error::AmbiguousOutput(names)) if names == ["DP-1", "DP-2"])
+    );
+    assert!(matches!(find("HDMI-A-1"), Err(Error::UnknownOutput(_))));
+}
+
+#[test]
+fn selects_toplevels_by_app_id_and_title() {
+    let (_mock, client) = connect(Config::default());
+    let find = |app_id: Option<&str>, title: Option<&str>, pick| {
+        let selector = ToplevelSelector {
+            app_id: app_id.map(|p| select::parse_pattern(p).unwrap()),
+            title: title.map(|p| select::parse_pattern(p).unwrap()),
+        };
+        client.find_toplevel(&selector, pick).map(|t| t.id.clone())
+    };
+    assert_eq!(find(Some("foot"), None, Pick::Error).unwrap(), "1");
+    assert_eq!(find(None, Some("/Firefox/"), Pick::Error).unwrap(), "2");
+    assert_eq!(find(Some("f*"), None, Pick::First).unwrap(), "1");
+    assert_eq!(find(Some("f*"), None, Pick::Newest).unwrap(), "2");
+    assert!(matches!(
+        find(Some("f*"), None, Pick::Error),
+        Err(Error::AmbiguousToplevel(_))
+    ));
+    assert!(matches!(
+        find(Some("kitty"), None, Pick::Error),
+        Err(Error::UnknownToplevel(_))
+    ));
+}
+
+#[test]
+fn captures_the_test_pattern() {
+    let (_mock, mut client) = connect(Config::default());
+    let id = client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    let frame = next_frame(&mut client, id).unwrap();
+    assert_eq!(frame.size, (64, 48));
+    assert_eq!(frame.format, BufferFormat::Shm(Format::Xrgb8888));
+    assert!(frame.presentation_time.is_some());
+    let rgba = client.read_rgba8(&frame).unwrap();
+    for (x, y) in [(0, 0), (63, 0), (10, 20), (63, 47)] {
+        let [_, r, g, b] = mock::pattern(x, y).to_be_bytes();
+        let i = ((y * 64 + x) * 4) as usize;
+        assert_eq!(rgba[i..i + 4], [r, g, b, 0xff], "pixel {x},{y}");
+    }
+}
+
+#[test]
+fn captures_toplevels() {
+    let (_mock, mut client) = connect(Config::default());
+    let source = Source::Toplevel("2".to_string());
+    let id = client
+        .create_session(&source, SessionOptions::default())
+        .unwrap();
+    assert_eq!(next_frame(&mut client, id).unwrap().size, (40, 30));
+}
+
+#[test]
+fn captures_the_pointer_cursor() {
+    let (_mock, mut client) = connect(Config::default());
+    let id = client
+        .create_cursor_session(&output(), SessionOptions::default())
+        .unwrap();
+    let (mut entered, mut position) = (false, None);
+    let frame = loop {
+        match next_event(&mut client, TIMEOUT).unwrap().unwrap() {
+            CaptureEvent::CursorEnter(session) => entered |= session == id,
+            CaptureEvent::CursorPosition { session, x, y } if session == id => {
+                position = Some((x, y))
+            }
+            CaptureEvent::Frame(frame) if frame.session == id => break frame,
+            _ => {}
+        }
+    };
+    assert!(entered);
+    assert_eq!(position, Some(CURSOR_POSITION));
+    assert_eq!(frame.size, CURSOR_SIZE);
+    let rgba = client.read_rgba8(&frame).unwrap();
+    assert_eq!(rgba.len(), (CURSOR_SIZE.0 * CURSOR_SIZE.1 * 4) as usize);
+}
+
+#[test]
+fn negotiates_the_shm_format() {
+    let config = Config {
+        shm_formats: vec![wayland_server::protocol::wl_shm::Format::Argb8888],
+        ..Default::default()
+    };
+    let (_mock, mut client) = connect(config);
+    let id = client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    assert_eq!(
+        next_frame(&mut client, id).unwrap().format,
+        BufferFormat::Shm(Format::Argb8888)
+    );
+
+    let options = SessionOptions {
+        shm_format: Some(Format::Abgr8888),
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    assert!(matches!(
+        next_frame(&mut client, id),
+        Err(Error::UnsupportedFormat { kind: "shm", offered }) if offered == ["Argb8888"]
+    ));
+}
+
+#[test]
+fn reallocates_buffers_after_a_resize() {
+    let (mock, mut client) = connect(Config::default());
+    let id = client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    let frame = next_frame(&mut client, id).unwrap();
+    assert_eq!(frame.size, (64, 48));
+    mock.with(|s| s.resize(&Target::Output("DP-1".to_string()), (80, 60)));
+    client.release(&frame);
+    loop {
+        let frame = next_frame(&mut client, id).unwrap();
+        client.release(&frame);
+        if frame.size == (80, 60) {
+            break;
+        }
+        assert_eq!(frame.size, (64, 48));
+    }
+}
+
+#[test]
+fn retries_failed_frames() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| {
+        s.failures
+            .extend([FailureReason::Unknown, FailureReason::Unknown])
+    });
+    let id = client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    let mut failed = 0;
+    loop {
+        match next_event(&mut client, TIMEOUT).unwrap().unwrap() {
+            CaptureEvent::FrameFailed { session, .. } if session == id => failed += 1,
+            CaptureEvent::Frame(frame) if frame.session == id => break,
+            _ => {}
+        }
+    }
+    assert_eq!(failed, 2);
+}
+
+#[test]
+fn gives_up_after_the_retry_limit() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| s.failures.extend([FailureReason::Unknown; 3]));
+    let options = SessionOptions {
+        max_retries: 2,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    assert!(matches!(
+        next_frame(&mut client, id),
+        Err(Error::CaptureFailed(2))
+    ));
+}
+
+#[test]
+fn reports_a_denied_capture() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| s.deny = true);
+    let id = client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    assert!(matches!(
+        next_frame(&mut client, id),
+        Err(Error::CaptureDenied)
+    ));
+}
+
+#[test]
+fn gives_up_after_the_restart_limit() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| s.deny = true);
+    let options = SessionOptions {
+        restart: true,
+        max_retries: 2,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    assert!(matches!(
+        next_frame(&mut client, id),
+        Err(Error::CaptureFailed(2))
+    ));
+}
+
+#[test]
+fn reports_an_early_stop_if_allowed() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| s.deny = true);
+    let options = SessionOptions {
+        allow_early_stop: true,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    loop {
+        match next_event(&mut client, TIMEOUT).unwrap().unwrap() {
+            CaptureEvent::Stopped(session) if session == id => break,
+            CaptureEvent::Frame(_) => panic!("captured a frame of a denied session"),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn reports_stopped_sessions() {
+    let (mock, mut client) = connect(Config::default());
+    let id = client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    next_frame(&mut client, id).unwrap();
+    mock.with(|s| s.stop(&Target::Output("DP-1".to_string())));
+    loop {
+        match next_event(&mut client, TIMEOUT).unwrap().unwrap() {
+            CaptureEvent::Stopped(session) if session == id => break,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn restarts_stopped_sessions() {
+    let (mock, mut client) = connect(Config::default());
+    let options = SessionOptions {
+        restart: true,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    let frame = next_frame(&mut client, id).unwrap();
+    client.release(&frame);
+    let captures = mock.with(|s| {
+        s.stop(&Target::Output("DP-1".to_string()));
+        s.captures
+    });
+    loop {
+        let frame = next_frame(&mut client, id).unwrap();
+        client.release(&frame);
+        if mock.with(|s| s.captures) > captures {
+            break;
+        }
+    }
+}
+
+#[test]
+fn reports_closed_toplevels() {
+    let (mock, mut client) = connect(Config::default());
+    let id = client
+        .create_session(
+            &Source::Toplevel("1".to_string()),
+            SessionOptions::default(),
+        )
+        .unwrap();
+    next_frame(&mut client, id).unwrap();
+    mock.with(|s| s.close_toplevel("1"));
+    let (mut closed, mut stopped) = (false, false);
+    while !closed || !stopped {
+        match next_event(&mut client, TIMEOUT).unwrap().unwrap() {
+            CaptureEvent::ToplevelClosed(t) => closed = t.id == "1",
+            CaptureEvent::Stopped(session) => stopped = session == id,
+            _ => {}
+        }
+    }
+    assert_eq!(client.toplevels().count(), 1);
+
+    mock.with(|s| s.add_toplevel(mock::toplevel("3", "foot", "~", (16, 16))));
+    loop {
+        let event = next_event(&mut client, TIMEOUT).unwrap();
+        if let CaptureEvent::ToplevelAdded(t) = event.expect("timed out waiting for the toplevel") {
+            assert_eq!((&t.id[..], &t.app_id[..]), ("3", "foot"));
+            break;
+        }
+    }
+}
+
+#[test]
+fn captures_on_demand() {
+    let (_mock, mut client) = connect(Config::default());
+    let options = SessionOptions {
+        pacing: Pacing::OnDemand,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    let idle = next_event(&mut client, Duration::from_millis(100)).unwrap();
+    assert!(!matches!(idle, Some(CaptureEvent::Frame(_))));
+    client.request_frame(id);
+    let frame = next_frame(&mut client, id).unwrap();
+    client.release(&frame);
+    let idle = next_event(&mut client, Duration::from_millis(100)).unwrap();
+    assert!(!matches!(idle, Some(CaptureEvent::Frame(_))));
+}
+
+#[test]
+fn limits_the_frame_rate() {
+    let (_mock, mut client) = connect(Config::default());
+    let options = SessionOptions {
+        pacing: Pacing::MaxFps(20.0),
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    let mut last = None;
+    for _ in 0..4 {
+        let frame = next_frame(&mut client, id).unwrap();
+        client.release(&frame);
+        if let Some(last) = last {
+            assert!(frame.capture_time - last >= Duration::from_millis(49));
+        }
+        last = Some(frame.capture_time);
+    }
+}
+
+#[test]
+fn rejects_an_invalid_frame_rate() {
+    let (_mock, mut client) = connect(Config::default());
+    for fps in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        let options = SessionOptions {
+            pacing: Pacing::MaxFps(fps),
+            ..Default::default()
+        };
+        let result = client.create_session(&output(), options);
+        assert!(matches!(result, Err(Error::InvalidOptions(_))), "{fps}");
+    }
+}
+
+#[test]
+fn reports_the_damage_of_each_frame() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| {
+        s.frame_damage
+            .extend([vec![(0, 0, 64, 48)], vec![(8, 4, 16, 12)]])
+    });
+    let options = SessionOptions {
+        pacing: Pacing::OnDemand,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    let mut damage = vec![];
+    for _ in 0..3 {
+        client.request_frame(id);
+        let frame = next_frame(&mut client, id).unwrap();
+        client.release(&frame);
+        damage.push(frame.damage.rects().to_vec());
+    }
+    assert_eq!(
+        damage,
+        [
+            vec![Rect::new(0, 0, 64, 48)],
+            vec![Rect::new(8, 4, 16, 12)],
+            vec![Rect::new(0, 0, 64, 48)],
+        ]
+    );
+}
+
+#[test]
+fn damages_each_buffer_with_the_frames_since_its_last_capture() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| {
+        s.frame_damage.extend([
+            vec![(0, 0, 64, 48)],
+            vec![(0, 0, 8, 8)],
+            vec![(8, 0, 8, 8), (16, 0, 8, 8)],
+        ])
+    });
+    let options = SessionOptions {
+        buffers: 2,
+        pacing: Pacing::OnDemand,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    // Holding each frame until the next one is ready alternates between the buffers.
+    client.request_frame(id);
+    let mut held = next_frame(&mut client, id).unwrap();
+    for _ in 0..3 {
+        client.request_frame(id);
+        let frame = next_frame(&mut client, id).unwrap();
+        client.release(&held);
+        held = frame;
+    }
+    let captures = mock.with(|s| s.capture_requests.clone());
+    let buffers: Vec<_> = captures.iter().map(|c| c.buffer).collect();
+    assert_eq!(buffers[0], buffers[2]);
+    assert_eq!(buffers[1], buffers[3]);
+    assert_ne!(buffers[0], buffers[1]);
+    let damage: Vec<_> = captures.into_iter().map(|c| c.damage).collect();
+    assert_eq!(
+        damage,
+        [
+            vec![(0, 0, 64, 48)],
+            vec![(0, 0, 64, 48)],
+            vec![(0, 0, 8, 8)],
+            vec![(8, 0, 8, 8), (16, 0, 8, 8)],
+        ]
+    );
+}
+
+#[test]
+fn keeps_one_frame_per_session_if_checking() {
+    let (_mock, mut client) = connect(Config::default());
+    let options = SessionOptions {
+        check: true,
+        ..Default::default()
+    };
+    let id = client.create_session(&output(), options).unwrap();
+    for _ in 0..5 {
+        let frame = next_frame(&mut client, id).unwrap();
+        client.release(&frame);
+    }
+}
+
+#[test]
+fn reports_damage_outside_the_buffer() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| s.extra_damage = Some((60, 40, 10, 10)));
+    client
+        .create_session(&output(), SessionOptions::default())
+        .unwrap();
+    loop {
+        let event = next_event(&mut client, TIMEOUT).unwrap();
+        if let CaptureEvent::Violation(v) = event.expect("timed out waiting for the violation") {
+            assert_eq!(v.rule, "damage outside the buffer");
+            break;
+        }
+    }
+}
+
+#[test]
+fn reports_events_after_ready_if_checking() {
+    let (mock, mut client) = connect(Config::default());
+    mock.with(|s| s.damage_after_ready = true);
+    let options = SessionOptions {
+        check: true,
+        ..Default::default()
+    };
+    client.create_session(&output(), options).unwrap();
+    loop {
+        let event = next_event(&mut client, TIMEOUT).unwrap();
+        if let CaptureEvent::Violation(v) = event.expect("timed out waiting for the violation") {
+            assert_eq!(v.rule, "event after ready or failed");
+            assert!(v.event.contains("Damage"), "{}", v.event);
+            break;
+        }
+    }
+}
+
+#[test]
+fn forwards_frame_damage_to_the_window() {
+    let (mock, stream) = Mock::serve(Config::default());
+    mock.with(|s| {
+        s.frame_damage
+            .extend([vec![(0, 0, 64, 48)], vec![(8, 4, 16, 12)]])
+    });
+    let fd = stream.as_raw_fd();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_wayland-copy-capture-test-client"));
+    command
+        .args(["--output", "DP-1"])
+        .env("WAYLAND_SOCKET", fd.to_string())
+        .env_remove("WAYLAND_DISPLAY");
+    // The socket is created with close-on-exec.
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let mut viewer = command.spawn().unwrap();
+    drop(stream);
+    let deadline = monotonic_now() + TIMEOUT;
+    while !mock.with(|s| s.surface_damage.contains(&(8, 4, 16, 12))) {
+        if monotonic_now() >= deadline {
+            viewer.kill().unwrap();
+            panic!("timed out waiting for the damage");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    mock.with(|s| s.close_windows());
+    assert!(viewer.wait().unwrap().success());
+}
@@ -0,0 +1,1016 @@
+//! A minimal compositor that runs on its own thread and serves a single client over a
+//! socketpair.
+//!
+//! Every capture completes immediately and fills the shm buffer with a pattern that
+//! encodes the pixel position, see [`pattern`].
+
+use {
+    std::{
+        collections::VecDeque,
+        fs::File,
+        os::{
+            fd::AsRawFd,
+            unix::{fs::FileExt, net::UnixStream},
+        },
+        sync::{
+            atomic::{AtomicBool, Ordering::Relaxed},
+            mpsc::{self, Receiver, Sender},
+            Arc, Mutex,
+        },
+        thread::{self, JoinHandle},
+    },
+    wayland_client::Connection,
+    wayland_copy_capture_test_client::monotonic_now,
+    wayland_protocols::{
+        ext::{
+            foreign_toplevel_list::v1::server::{
+                ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1,
+                ext_foreign_toplevel_list_v1::{self, ExtForeignToplevelListV1},
+            },
+            image_capture_source::v1::server::{
+                ext_foreign_toplevel_image_capture_source_manager_v1::{
+                    self, ExtForeignToplevelImageCaptureSourceManagerV1,
+                },
+                ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+                ext_output_image_capture_source_manager_v1::{
+                    self, ExtOutputImageCaptureSourceManagerV1,
+                },
+            },
+            image_copy_capture::v1::server::{
+                ext_image_copy_capture_cursor_session_v1::{
+                    self, ExtImageCopyCaptureCursorSessionV1,
+                },
+                ext_image_copy_capture_frame_v1::{
+                    self, ExtImageCopyCaptureFrameV1, FailureReason,
+                },
+                ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+                ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+            },
+        },
+        wp::{
+            single_pixel_buffer::v1::server::wp_single_pixel_buffer_manager_v1::{
+                self, WpSinglePixelBufferManagerV1,
+            },
+            viewporter::server::{
+                wp_viewport::WpViewport,
+                wp_viewporter::{self, WpViewporter},
+            },
+        },
+        xdg::shell::server::{
+            xdg_surface::{self, XdgSurface},
+            xdg_toplevel::XdgToplevel,
+            xdg_wm_base::{self, XdgWmBase},
+        },
+    },
+    wayland_server::{
+        backend::ClientData,
+        protocol::{
+            wl_buffer::WlBuffer,
+            wl_callback::WlCallback,
+            wl_compositor::{self, WlCompositor},
+            wl_output::{self, Subpixel, Transform, WlOutput},
+            wl_pointer::WlPointer,
+            wl_seat::{self, WlSeat},
+            wl_shm::{self, Format, WlShm},
+            wl_shm_pool::{self, WlShmPool},
+            wl_subcompositor::{self, WlSubcompositor},
+            wl_subsurface::WlSubsurface,
+            wl_surface::{self, WlSurface},
+        },
+        Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+    },
+};
+
+pub const CURSOR_SIZE: (i32, i32) = (16, 16);
+/// The position sent when a pointer cursor session is created.
+pub const CURSOR_POSITION: (i32, i32) = (10, 20);
+
+#[derive(Clone)]
+pub struct MockOutput {
+    pub name: String,
+    pub description: String,
+    pub make: String,
+    pub model: String,
+    pub size: (i32, i32),
+}
+
+#[derive(Clone)]
+pub struct MockToplevel {
+    pub id: String,
+    pub app_id: String,
+    pub title: String,
+    pub size: (i32, i32),
+}
+
+pub struct Config {
+    pub outputs: Vec<MockOutput>,
+    pub toplevels: Vec<MockToplevel>,
+    /// The offered shm formats. Captures fail with `buffer_constraints` for any but
+    /// xrgb8888 and argb8888.
+    pub shm_formats: Vec<Format>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            outputs: vec![output("DP-1", "Dell U2720Q", (64, 48))],
+            toplevels: vec![
+                toplevel("1", "foot", "~", (32, 24)),
+                toplevel("2", "firefox", "Mozilla Firefox", (40, 30)),
+            ],
+            shm_formats: vec![Format::Xrgb8888, Format::Argb8888],
+        }
+    }
+}
+
+pub fn output(name: &str, model: &str, size: (i32, i32)) -> MockOutput {
+    MockOutput {
+        name: name.to_string(),
+        description: format!("{model} ({name})"),
+        make: "Mock".to_string(),
+        model: model.to_string(),
+        size,
+    }
+}
+
+pub fn toplevel(id: &str, app_id: &str, title: &str, size: (i32, i32)) -> MockToplevel {
+    MockToplevel {
+        id: id.to_string(),
+        app_id: app_id.to_string(),
+        title: title.to_string(),
+        size,
+    }
+}
+
+/// The xrgb8888 value of a pixel of the test pattern.
+pub fn pattern(x: i32, y: i32) -> u32 {
+    0xff00_0000 | (x as u32 & 0xff) << 16 | (y as u32 & 0xff) << 8 | 0x80
+}
+
+/// What a capture source refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Output(String),
+    Toplevel(String),
+    Cursor,
+}
+
+/// The compositor state. It only exists on the server thread and is reached through
+/// [`Mock::with`].
+pub struct Server {
+    dh: DisplayHandle,
+    pub outputs: Vec<MockOutput>,
+    pub toplevels: Vec<MockToplevel>,
+    pub shm_formats: Vec<Format>,
+    toplevel_lists: Vec<ExtForeignToplevelListV1>,
+    toplevel_handles: Vec<ExtForeignToplevelHandleV1>,
+    sessions: Vec<ExtImageCopyCaptureSessionV1>,
+    /// Failure reasons of the next captures.
+    pub failures: VecDeque<FailureReason>,
+    /// Stop new sessions right away.
+    pub deny: bool,
+    /// Damage of the next captures. Captures without an entry damage the whole buffer.
+    pub frame_damage: VecDeque<Vec<(i32, i32, i32, i32)>>,
+    /// Damage sent with every frame in addition to the damage above.
+    pub extra_damage: Option<(i32, i32, i32, i32)>,
+    /// Send damage after ready, which the specification does not allow.
+    pub damage_after_ready: bool,
+    /// Number of successful captures.
+    pub captures: u64,
+    /// Every capture request in order.
+    pub capture_requests: Vec<CaptureRequest>,
+    /// The damage_buffer requests of all surfaces in order.
+    pub surface_damage: Vec<(i32, i32, i32, i32)>,
+    windows: Vec<XdgToplevel>,
+}
+
+/// A capture request and what the client attached to the frame before it.
+#[derive(Clone, Debug)]
+pub struct CaptureRequest {
+    /// The protocol id of the buffer.
+    pub buffer: u32,
+    /// The damage_buffer requests.
+    pub damage: Vec<(i32, i32, i32, i32)>,
+}
+
+struct SessionData {
+    target: Target,
+    stopped: AtomicBool,
+    frame: Mutex<Option<ExtImageCopyCaptureFrameV1>>,
+}
+
+struct ShmBuffer {
+    file: Arc<File>,
+    offset: i32,
+    size: (i32, i32),
+    stride: i32,
+    format: WEnum<Format>,
+}
+
+struct FrameState {
+    session: ExtImageCopyCaptureSessionV1,
+    buffer: Option<WlBuffer>,
+    damage: Vec<(i32, i32, i32, i32)>,
+}
+
+type Command = Box<dyn FnOnce(&mut Server) + Send>;
+
+/// A running mock compositor. Dropping it stops the server thread.
+pub struct Mock {
+    commands: Sender<Command>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct NoClientData;
+
+impl ClientData for NoClientData {}
+
+impl Mock {
+    /// Starts the compositor and returns it with the client end of the connection.
+    pub fn start(config: Config) -> (Self, Connection) {
+        let (mock, stream) = Self::serve(config);
+        (mock, Connection::from_socket(stream).unwrap())
+    }
+
+    /// Starts the compositor and returns it with the client end of the socket, e.g. to
+    /// pass it to another process in `WAYLAND_SOCKET`.
+    pub fn serve(config: Config) -> (Self, UnixStream) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let (commands, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = stop.clone();
+            move || run(config, server_stream, rx, &stop)
+        });
+        let mock = Self {
+            commands,
+            stop,
+            thread: Some(thread),
+        };
+        (mock, client_stream)
+    }
+
+    /// Runs `f` on the server thread and waits for it to complete.
+    pub fn with<R: Send + 'static>(&self, f: impl FnOnce(&mut Server) -> R + Send + 'static) -> R {
+        let (tx, rx) = mpsc::channel();
+        self.commands
+            .send(Box::new(move |server| tx.send(f(server)).unwrap()))
+            .expect("the mock compositor has stopped");
+        rx.recv().expect("the mock compositor has stopped")
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        self.stop.store(true, Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn run(config: Config, stream: UnixStream, commands: Receiver<Command>, stop: &AtomicBool) {
+    let mut display = Display::<Server>::new().unwrap();
+    let mut dh = display.handle();
+    dh.create_global::<Server, WlCompositor, ()>(4, ());
+    dh.create_global::<Server, WlSubcompositor, ()>(1, ());
+    dh.create_global::<Server, WlShm, ()>(1, ());
+    dh.create_global::<Server, WlSeat, ()>(5, ());
+    dh.create_global::<Server, XdgWmBase, ()>(1, ());
+    dh.create_global::<Server, WpViewporter, ()>(1, ());
+    dh.create_global::<Server, WpSinglePixelBufferManagerV1, ()>(1, ());
+    for output in &config.outputs {
+        dh.create_global::<Server, WlOutput, String>(4, output.name.clone());
+    }
+    dh.create_global::<Server, ExtForeignToplevelListV1, ()>(1, ());
+    dh.create_global::<Server, ExtOutputImageCaptureSourceManagerV1, ()>(1, ());
+    dh.create_global::<Server, ExtForeignToplevelImageCaptureSourceManagerV1, ()>(1, ());
+    dh.create_global::<Server, ExtImageCopyCaptureManagerV1, ()>(1, ());
+    dh.insert_client(stream, Arc::new(NoClientData)).unwrap();
+    let mut server = Server {
+        dh: dh.clone(),
+        outputs: config.outputs,
+        toplevels: config.toplevels,
+        shm_formats: config.shm_formats,
+        toplevel_lists: vec![],
+        toplevel_handles: vec![],
+        sessions: vec![],
+        failures: Default::default(),
+        deny: false,
+        frame_damage: Default::default(),
+        extra_damage: None,
+        damage_after_ready: false,
+        captures: 0,
+        capture_requests: vec![],
+        surface_damage: vec![],
+        windows: vec![],
+    };
+    while !stop.load(Relaxed) {
+        let mut fd = libc::pollfd {
+            fd: display.backend().poll_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe {
+            libc::poll(&mut fd, 1, 5);
+        }
+        if let Err(e) = display.dispatch_clients(&mut server) {
+            // Dropping the display closes the connection, so the client fails instead of
+            // waiting for events that never come.
+            eprintln!("mock compositor: {e}");
+            break;
+        }
+        while let Ok(command) = commands.try_recv() {
+            command(&mut server);
+        }
+        // The client may already be gone at the end of a test.
+        let _ = display.flush_clients();
+    }
+}
+
+impl Server {
+    fn size(&self, target: &Target) -> Option<(i32, i32)> {
+        match target {
+            Target::Output(name) => self
+                .outputs
+                .iter()
+                .find(|o| &o.name == name)
+                .map(|o| o.size),
+            Target::Toplevel(id) => self.toplevels.iter().find(|t| &t.id == id).map(|t| t.size),
+            Target::Cursor => Some(CURSOR_SIZE),
+        }
+    }
+
+    fn sessions_of(&mut self, target: &Target) -> Vec<ExtImageCopyCaptureSessionV1> {
+        self.sessions.retain(|s| s.is_alive());
+        self.sessions
+            .iter()
+            .filter(|s| &s.data::<SessionData>().unwrap().target == target)
+            .cloned()
+            .collect()
+    }
+
+    fn send_constraints(&self, session: &ExtImageCopyCaptureSessionV1, size: (i32, i32)) {
+        session.buffer_size(size.0 as u32, size.1 as u32);
+        for &format in &self.shm_formats {
+            session.shm_format(format);
+        }
+        session.done();
+    }
+
+    /// Changes the size of an output or toplevel and sends the new buffer constraints.
+    pub fn resize(&mut self, target: &Target, size: (i32, i32)) {
+        match target {
+            Target::Output(name) => {
+                let o = self.outputs.iter_mut().find(|o| &o.name == name).unwrap();
+                o.size = size;
+            }
+            Target::Toplevel(id) => {
+                let t = self.toplevels.iter_mut().find(|t| &t.id == id).unwrap();
+                t.size = size;
+            }
+            Target::Cursor => panic!("the cursor cannot be resized"),
+        }
+        for session in self.sessions_of(target) {
+            self.send_constraints(&session, size);
+        }
+    }
+
+    /// Stops the sessions that capture the target.
+    pub fn stop(&mut self, target: &Target) {
+        for session in self.sessions_of(target) {
+            let data = session.data::<SessionData>().unwrap();
+            if !data.stopped.swap(true, Relaxed) {
+                session.stopped();
+            }
+        }
+    }
+
+    /// Asks the client to close its windows.
+    pub fn close_windows(&mut self) {
+        self.windows.retain(|w| w.is_alive());
+        for window in &self.windows {
+            window.close();
+        }
+    }
+
+    pub fn add_toplevel(&mut self, toplevel: MockToplevel) {
+        self.toplevels.push(toplevel.clone());
+        for list in self.toplevel_lists.clone() {
+            self.announce(&list, &toplevel);
+        }
+    }
+
+    /// Closes the toplevel and stops its sessions.
+    pub fn close_toplevel(&mut self, id: &str) {
+        self.toplevels.retain(|t| t.id != id);
+        self.toplevel_handles.retain(|h| {
+            if h.data::<String>().unwrap() != id {
+                return true;
+            }
+            h.closed();
+            false
+        });
+        self.stop(&Target::Toplevel(id.to_string()));
+    }
+
+    fn announce(&mut self, list: &ExtForeignToplevelListV1, toplevel: &MockToplevel) {
+        let Some(client) = list.client() else {
+            return;
+        };
+        let handle = client
+            .create_resource::<ExtForeignToplevelHandleV1, _, Self>(
+                &self.dh,
+                list.version(),
+                toplevel.id.clone(),
+            )
+            .unwrap();
+        list.toplevel(&handle);
+        handle.identifier(toplevel.id.clone());
+        handle.app_id(toplevel.app_id.clone());
+        handle.title(toplevel.title.clone());
+        handle.done();
+        self.toplevel_handles.push(handle);
+    }
+
+    fn create_session(
+        &mut self,
+        session: New<ExtImageCopyCaptureSessionV1>,
+        target: Target,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let size = self.size(&target);
+        let session = data_init.init(
+            session,
+            SessionData {
+                target,
+                stopped: AtomicBool::new(false),
+                frame: Mutex::new(None),
+            },
+        );
+        match size {
+            Some(size) if !self.deny => self.send_constraints(&session, size),
+            _ => {
+                session
+                    .data::<SessionData>()
+                    .unwrap()
+                    .stopped
+                    .store(true, Relaxed);
+                session.stopped();
+            }
+        }
+        self.sessions.push(session);
+    }
+
+    fn capture(&mut self, frame: &ExtImageCopyCaptureFrameV1, state: &FrameState) {
+        if let Some(buffer) = &state.buffer {
+            self.capture_requests.push(CaptureRequest {
+                buffer: buffer.id().protocol_id(),
+                damage: state.damage.clone(),
+            });
+        }
+        let session = state.session.data::<SessionData>().unwrap();
+        let size = match self.size(&session.target) {
+            Some(size) if !session.stopped.load(Relaxed) => size,
+            _ => return frame.failed(FailureReason::Stopped),
+        };
+        if let Some(reason) = self.failures.pop_front() {
+            return frame.failed(reason);
+        }
+        let Some(buffer) = state.buffer.as_ref().and_then(|b| b.data::<ShmBuffer>()) else {
+            return frame.failed(FailureReason::BufferConstraints);
+        };
+        // Only opaque patterns are drawn, so both formats have the same contents.
+        match buffer.format {
+            WEnum::Value(format @ (Format::Xrgb8888 | Format::Argb8888))
+                if self.shm_formats.contains(&format) => {}
+            _ => return frame.failed(FailureReason::BufferConstraints),
+        }
+        if buffer.size != size || buffer.stride < size.0 * 4 {
+            return frame.failed(FailureReason::BufferConstraints);
+        }
+        for y in 0..size.1 {
+            let row: Vec<u8> = (0..size.0)
+                .flat_map(|x| pattern(x, y).to_le_bytes())
+                .collect();
+            let offset = buffer.offset + y * buffer.stride;
+            buffer.file.write_all_at(&row, offset as u64).unwrap();
+        }
+        let now = monotonic_now();
+        frame.transform(Transform::Normal);
+        let damage = self
+            .frame_damage
+            .pop_front()
+            .unwrap_or_else(|| vec![(0, 0, size.0, size.1)]);
+        for (x, y, width, height) in damage.into_iter().chain(self.extra_damage) {
+            frame.damage(x, y, width, height);
+        }
+        frame.presentation_time(
+            (now.as_secs() >> 32) as u32,
+            now.as_secs() as u32,
+            now.subsec_nanos(),
+        );
+        frame.ready();
+        if self.damage_after_ready {
+            frame.damage(0, 0, size.0, size.1);
+        }
+        self.captures += 1;
+    }
+}
+
+/// Implements [`Dispatch`] for interfaces whose requests need no handling.
+macro_rules! ignore {
+    ($($interface:ty, $data:ty;)*) => {
+        $(
+            impl Dispatch<$interface, $data> for Server {
+                fn request(
+                    _: &mut Self,
+                    _: &Client,
+                    _: &$interface,
+                    _: <$interface as Resource>::Request,
+                    _: &$data,
+                    _: &DisplayHandle,
+                    _: &mut DataInit<'_, Self>,
+                ) {
+                }
+            }
+        )*
+    };
+}
+
+ignore! {
+    WlCallback, ();
+    WlSubsurface, ();
+    WlPointer, ();
+    WpViewport, ();
+    WlBuffer, ();
+    WlBuffer, ShmBuffer;
+    WlOutput, String;
+    XdgToplevel, ();
+    ExtImageCaptureSourceV1, Target;
+    ExtForeignToplevelHandleV1, String;
+}
+
+/// Implements [`GlobalDispatch`] for globals that keep no per-client state.
+macro_rules! global {
+    ($($interface:ty;)*) => {
+        $(
+            impl GlobalDispatch<$interface, ()> for Server {
+                fn bind(
+                    _: &mut Self,
+                    _: &DisplayHandle,
+                    _: &Client,
+                    resource: New<$interface>,
+                    _: &(),
+                    data_init: &mut DataInit<'_, Self>,
+                ) {
+                    data_init.init(resource, ());
+                }
+            }
+        )*
+    };
+}
+
+global! {
+    WlCompositor;
+    WlSubcompositor;
+    XdgWmBase;
+    WpViewporter;
+    WpSinglePixelBufferManagerV1;
+    ExtOutputImageCaptureSourceManagerV1;
+    ExtForeignToplevelImageCaptureSourceManagerV1;
+    ExtImageCopyCaptureManagerV1;
+}
+
+impl Dispatch<WlCompositor, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlCompositor,
+        request: wl_compositor::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_compositor::Request::CreateSurface { id } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl Dispatch<WlSurface, ()> for Server {
+    fn request(
+        server: &mut Self,
+        _: &Client,
+        _: &WlSurface,
+        request: wl_surface::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_surface::Request::Frame { callback } => {
+                data_init.init(callback, ()).done(0);
+            }
+            wl_surface::Request::DamageBuffer {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                server.surface_damage.push((x, y, width, height));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlSubcompositor, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlSubcompositor,
+        request: wl_subcompositor::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_subcompositor::Request::GetSubsurface { id, .. } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl Dispatch<WpViewporter, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WpViewporter,
+        request: wp_viewporter::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wp_viewporter::Request::GetViewport { id, .. } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl Dispatch<WpSinglePixelBufferManagerV1, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WpSinglePixelBufferManagerV1,
+        request: wp_single_pixel_buffer_manager_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wp_single_pixel_buffer_manager_v1::Request::CreateU32RgbaBuffer { id, .. } = request
+        {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl GlobalDispatch<WlSeat, ()> for Server {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlSeat>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let seat = data_init.init(resource, ());
+        seat.capabilities(wl_seat::Capability::Pointer);
+    }
+}
+
+impl Dispatch<WlSeat, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlSeat,
+        request: wl_seat::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_seat::Request::GetPointer { id } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl GlobalDispatch<WlShm, ()> for Server {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlShm>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let shm = data_init.init(resource, ());
+        for &format in &state.shm_formats {
+            shm.format(format);
+        }
+    }
+}
+
+impl Dispatch<WlShm, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlShm,
+        request: wl_shm::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm::Request::CreatePool { id, fd, .. } = request {
+            data_init.init(id, Arc::new(File::from(fd)));
+        }
+    }
+}
+
+impl Dispatch<WlShmPool, Arc<File>> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlShmPool,
+        request: wl_shm_pool::Request,
+        file: &Arc<File>,
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm_pool::Request::CreateBuffer {
+            id,
+            offset,
+            width,
+            height,
+            stride,
+            format,
+        } = request
+        {
+            let buffer = ShmBuffer {
+                file: file.clone(),
+                offset,
+                size: (width, height),
+                stride,
+                format,
+            };
+            data_init.init(id, buffer);
+        }
+    }
+}
+
+impl GlobalDispatch<WlOutput, String> for Server {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlOutput>,
+        name: &String,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let output = data_init.init(resource, name.clone());
+        let o = state.outputs.iter().find(|o| &o.name == name).unwrap();
+        output.geometry(
+            0,
+            0,
+            600,
+            340,
+            Subpixel::Unknown,
+            o.make.clone(),
+            o.model.clone(),
+            Transform::Normal,
+        );
+        output.mode(wl_output::Mode::Current, o.size.0, o.size.1, 60000);
+        output.scale(1);
+        output.name(o.name.clone());
+        output.description(o.description.clone());
+        output.done();
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &XdgWmBase,
+        request: xdg_wm_base::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let xdg_wm_base::Request::GetXdgSurface { id, .. } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, ()> for Server {
+    fn request(
+        server: &mut Self,
+        _: &Client,
+        xdg_surface: &XdgSurface,
+        request: xdg_surface::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let xdg_surface::Request::GetToplevel { id } = request {
+            let window = data_init.init(id, ());
+            window.configure(0, 0, vec![]);
+            xdg_surface.configure(0);
+            server.windows.push(window);
+        }
+    }
+}
+
+impl GlobalDispatch<ExtForeignToplevelListV1, ()> for Server {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<ExtForeignToplevelListV1>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let list = data_init.init(resource, ());
+        for toplevel in state.toplevels.clone() {
+            state.announce(&list, &toplevel);
+        }
+        state.toplevel_lists.push(list);
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        list: &ExtForeignToplevelListV1,
+        request: ext_foreign_toplevel_list_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_foreign_toplevel_list_v1::Request::Stop = request {
+            state.toplevel_lists.retain(|l| l != list);
+            list.finished();
+        }
+    }
+}
+
+impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &ExtOutputImageCaptureSourceManagerV1,
+        request: ext_output_image_capture_source_manager_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_output_image_capture_source_manager_v1::Request::CreateSource {
+            source,
+            output,
+        } = request
+        {
+            let name = output.data::<String>().unwrap().clone();
+            data_init.init(source, Target::Output(name));
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &ExtForeignToplevelImageCaptureSourceManagerV1,
+        request: ext_foreign_toplevel_image_capture_source_manager_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_foreign_toplevel_image_capture_source_manager_v1::Request::CreateSource {
+            source,
+            toplevel_handle,
+        } = request
+        {
+            let id = toplevel_handle.data::<String>().unwrap().clone();
+            data_init.init(source, Target::Toplevel(id));
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ExtImageCopyCaptureManagerV1,
+        request: ext_image_copy_capture_manager_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_image_copy_capture_manager_v1::Request::CreateSession {
+                session, source, ..
+            } => {
+                let target = source.data::<Target>().unwrap().clone();
+                state.create_session(session, target, data_init);
+            }
+            ext_image_copy_capture_manager_v1::Request::CreatePointerCursorSession {
+                session,
+                ..
+            } => {
+                let session = data_init.init(session, ());
+                session.enter();
+                session.position(CURSOR_POSITION.0, CURSOR_POSITION.1);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureCursorSessionV1, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ExtImageCopyCaptureCursorSessionV1,
+        request: ext_image_copy_capture_cursor_session_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_image_copy_capture_cursor_session_v1::Request::GetCaptureSession { session } =
+            request
+        {
+            state.create_session(session, Target::Cursor, data_init);
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, SessionData> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        session: &ExtImageCopyCaptureSessionV1,
+        request: ext_image_copy_capture_session_v1::Request,
+        data: &SessionData,
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_image_copy_capture_session_v1::Request::CreateFrame { frame } = request {
+            let state = FrameState {
+                session: session.clone(),
+                buffer: None,
+                damage: vec![],
+            };
+            let frame = data_init.init(frame, Mutex::new(state));
+            let mut live = data.frame.lock().unwrap();
+            if live.as_ref().is_some_and(|f| f.is_alive()) {
+                session.post_error(
+                    ext_image_copy_capture_session_v1::Error::DuplicateFrame,
+                    "the session already has a frame",
+                );
+                return;
+            }
+            *live = Some(frame);
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, Mutex<FrameState>> for Server {
+    fn request(
+        server: &mut Self,
+        _: &Client,
+        frame: &ExtImageCopyCaptureFrameV1,
+        request: ext_image_copy_capture_frame_v1::Request,
+        state: &Mutex<FrameState>,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        let mut state = state.lock().unwrap();
+        match request {
+            ext_image_copy_capture_frame_v1::Request::AttachBuffer { buffer } => {
+                state.buffer = Some(buffer);
+            }
+            ext_image_copy_capture_frame_v1::Request::DamageBuffer {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                state.damage.push((x, y, width, height));
+            }
+            ext_image_copy_capture_frame_v1::Request::Capture => server.capture(frame, &state),
+            _ => {}
+        }
+    }
+}